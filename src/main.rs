@@ -19,6 +19,83 @@ pub enum BacklightError{
     BrightnessCtlNotInstalled(std::io::Error)
 }
 
+// decoded images are kept across refreshes, only the ones still shown survive the next one
+#[derive(Default)]
+struct ImageCache {
+    current: std::collections::HashMap<std::path::PathBuf, slint::Image>,
+    previous: std::collections::HashMap<std::path::PathBuf, slint::Image>,
+}
+
+impl ImageCache {
+    fn get(&mut self, path: Option<&std::path::PathBuf>) -> slint::Image {
+        let Some(path) = path else { return slint::Image::default() };
+        let image = match self.current.get(path).cloned().or_else(|| self.previous.remove(path)) {
+            Some(image) => image,
+            // failures aren't kept, the file may still be written
+            None => match slint::Image::load_from_path(path) {
+                Ok(image) => image,
+                Err(_) => return slint::Image::default(),
+            },
+        };
+        self.current.insert(path.clone(), image.clone());
+        image
+    }
+
+    fn next(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+}
+
+// the headers are looked up on a blocking thread so the event loop only builds the models
+async fn notification_groups(
+    data: std::sync::Arc<tokio::sync::RwLock<services::notifications::NotificationsData>>,
+    apps: std::sync::Arc<tokio::sync::RwLock<services::applications::ApplicationsData>>,
+    headers: std::sync::Arc<std::sync::Mutex<services::notifications::GroupHeaders>>,
+) -> Vec<services::notifications::NotificationGroup> {
+    tokio::task::spawn_blocking(move || {
+        let mut headers = headers.lock().unwrap();
+        data.blocking_read().groups(&apps.blocking_read(), &mut headers)
+    }).await.unwrap_or_default()
+}
+
+fn notification_entry(entry: &services::notifications::HistoryEntry, images: &mut ImageCache) -> NotificationEntry {
+    NotificationEntry {
+        id: entry.id as i32,
+        app_name: entry.app_name.clone().into(),
+        summary: entry.summary.clone().into(),
+        body: entry.styled_body.text().into(),
+        body_spans: std::rc::Rc::new(slint::VecModel::from(
+            entry.styled_body.spans.iter().map(|x| TextSpan {
+                text: x.text.clone().into(),
+                bold: x.bold,
+                italic: x.italic,
+                underline: x.underline,
+                link: x.link.clone().unwrap_or_default().into(),
+            }).collect::<Vec<_>>()
+        )).into(),
+        image: images.get(entry.image.as_ref()),
+        time: chrono::DateTime::from_timestamp(entry.time, 0)
+            .map(|x| slint::format!("{}", x.with_timezone(&Local).format("%H:%M")))
+            .unwrap_or_default(),
+        read: entry.read,
+    }
+}
+
+fn notification_group(group: &services::notifications::NotificationGroup, images: &mut ImageCache) -> NotificationGroup {
+    NotificationGroup {
+        key: group.key.clone().into(),
+        name: group.name.clone().into(),
+        icon: images.get(group.icon.as_ref()),
+        count: group.len() as i32,
+        hidden: group.hidden() as i32,
+        unread: group.unread as i32,
+        collapsed: group.collapsed,
+        entries: std::rc::Rc::new(slint::VecModel::from(
+            group.entries.iter().map(|x| notification_entry(x, images)).collect::<Vec<_>>()
+        )).into(),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), slint::PlatformError> {
     let ui = AppWindow::new()?;
@@ -69,10 +146,93 @@ async fn main() -> Result<(), slint::PlatformError> {
 
     // let mut pawrapper = services::audio::PulseWrapper::new();
     // let x = pawrapper.get_sources();
-    let audio_service = services::audio::AudioService::new().unwrap();
+    let audio_service = services::audio::AudioService::new().await.unwrap();
     let application_service = services::applications::ApplicationService::new().await.unwrap();
     // dbg!(&application_service.read().await.data);
-    let hyprland_service = services::hyprland::HyprlandService::new();
+    let compositor_service = match services::compositor::from_env().await {
+        Ok(service) => Some(service),
+        Err(e) => {
+            log::warn!(target: "compositor", "running without a compositor: {:?}", e);
+            None
+        }
+    };
+    // another notification daemon may own the name already
+    let notification_service = match services::notifications::NotificationService::new().await {
+        Ok(service) => Some(service),
+        Err(e) => {
+            log::warn!(target: "notifications", "running without notifications: {:?}", e);
+            None
+        }
+    };
+    if let Some(notification_service) = &notification_service {
+        let history = std::rc::Rc::new(slint::VecModel::<NotificationEntry>::default()); // newest first
+        let groups = std::rc::Rc::new(slint::VecModel::<NotificationGroup>::default());
+        let adapter = ui.global::<NotificationAdapter>();
+        adapter.set_history(history.clone().into());
+        adapter.set_groups(groups.clone().into());
+
+        let mut refresh = {
+            let ui_handle = ui.as_weak();
+            let mut images = ImageCache::default();
+            move |data: &services::notifications::NotificationsData, new_groups: &[services::notifications::NotificationGroup]| {
+                let ui = ui_handle.unwrap();
+                images.next();
+                history.set_vec(data.history.iter().rev().map(|x| notification_entry(x, &mut images)).collect::<Vec<_>>());
+                groups.set_vec(new_groups.iter().map(|x| notification_group(x, &mut images)).collect::<Vec<_>>());
+                ui.global::<NotificationAdapter>().set_unread(data.unread() as i32);
+            }
+        };
+        let headers = std::sync::Arc::new(std::sync::Mutex::new(services::notifications::GroupHeaders::new()));
+        let apps = application_service.read().await.data.clone();
+        let data = notification_service.read().await.data.clone();
+        let new_groups = notification_groups(data.clone(), apps.clone(), headers.clone()).await;
+        refresh(&*data.read().await, &new_groups);
+
+        macro_rules! on_notification {
+            ($callback:ident, |$service:ident $(, $arg:ident)*| $body:expr) => {
+                adapter.$callback({
+                    let notification_service = notification_service.clone();
+                    move |$($arg),*| {
+                        let $service = notification_service.clone();
+                        tokio::spawn(async move { $body });
+                    }
+                });
+            };
+        }
+        on_notification!(on_mark_read, |s, id| s.write().await.mark_read(id as u32).await);
+        on_notification!(on_mark_all_read, |s| s.write().await.mark_all_read().await);
+        on_notification!(on_clear, |s, id| s.write().await.clear(id as u32).await);
+        on_notification!(on_clear_app, |s, app| s.write().await.clear_app(&app).await);
+        on_notification!(on_clear_all, |s| s.write().await.clear_all().await);
+        on_notification!(on_toggle_group, |s, key| s.write().await.toggle_group(&key).await);
+        on_notification!(on_dismiss_group, |s, key| s.write().await.dismiss_group(&key).await);
+
+        // any D-Bus client can send links, so only web and mail links are handed to xdg-open
+        adapter.on_open_link(|url| {
+            let scheme = url.split_once(':').map(|(x, _)| x.to_ascii_lowercase()).unwrap_or_default();
+            if !matches!(scheme.as_str(), "http" | "https" | "mailto") {
+                log::warn!(target: "notifications", "not opening {}: unsupported scheme", url);
+                return
+            }
+            if let Err(e) = std::process::Command::new("xdg-open").arg(url.as_str()).spawn() {
+                log::warn!(target: "notifications", "couldn't open {}: {:?}", url, e);
+            }
+        });
+
+        let mut history_rx = notification_service.read().await.sender.history.subscribe();
+        slint::spawn_local(async move {
+            loop {
+                let data = match history_rx.recv().await {
+                    Ok(data) => data,
+                    // every message carries the whole state, so skipped ones don't matter
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                };
+                let new_groups = notification_groups(data.clone(), apps.clone(), headers.clone()).await;
+                refresh(&*data.read().await, &new_groups);
+            }
+        }).unwrap();
+    }
 
 
 
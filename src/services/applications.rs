@@ -152,6 +152,20 @@ impl ApplicationsData {
         apps
     }
 
+    // desktop entry ids are the file name without .desktop, e.g. from the notification hint
+    pub fn by_desktop_entry<'a>(&'a self, id: &str) -> Option<&'a Application> {
+        let id = id.strip_suffix(".desktop").unwrap_or(id);
+        self.apps.iter().find(|x| x.desktop.file_stem() == Some(OsStr::new(id)))
+    }
+
+    // fallback for senders that only give their name
+    pub fn by_name<'a>(&'a self, name: &str) -> Option<&'a Application> {
+        self.apps.iter().find(|x|
+            x.name.eq_ignore_ascii_case(name) ||
+            x.startup_wm_class.as_ref().is_some_and(|c| c.eq_ignore_ascii_case(name))
+        )
+    }
+
     pub fn get_frequencies<'a>(&'a self) -> HashMap<&'a String, usize> {
         let mut h = HashMap::new();
         for i in &self.apps {
@@ -1,308 +1,1080 @@
-use std::{ops::Deref, sync::{Arc, Mutex}};
+use std::{collections::{HashMap, HashSet, VecDeque}, env, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}};
 
-use pulse::{context::{introspect::{Introspector, SinkInputInfo, SourceOutputInfo}, Context}, def::{SinkState, SourceState}, mainloop::standard::{IterateResult, Mainloop}, operation::{Operation, State}, proplist::Proplist};
-use pulse::context::{FlagSet as ContextFlagSet};
+use log::{debug, info, warn};
+use pulse::{def::{PortAvailable, SinkState, SourceState}, error::PAErr};
+use pulse::channelmap::Map as ChannelMap;
+use pulse::context::subscribe::{Facility, Operation as SubscribeOperation};
+use pulse::volume::{ChannelVolumes, Volume};
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{broadcast::{channel, Receiver, Sender}, mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, Mutex, RwLock};
 
+use super::pipewire::PipewireBackend;
+use super::pulseaudio::PulseBackend;
+use super::utils::{async_file_watcher, BoxFuture, PathGetter};
 
-#[derive(Clone, Debug)]
+
+// the name of our own client, e.g. to skip our own streams
+pub const APP_NAME: &str = "ekslistence";
+
+// peak-detect streams deliver one peak per sample, so this is also their update rate
+const PEAK_RATE: u32 = 30;
+const SPECTRUM_RATE: u32 = 44100;
+const FFT_SIZE: usize = 2048;
+// how much of the last bar height is kept per update, so bars fall smoothly
+const BAR_FALLOFF: f32 = 0.85;
+// how often a meter without samples (e.g. of a suspended device) checks for receivers
+const METER_IDLE_CHECK: Duration = Duration::from_secs(1);
+// manual default choices that are remembered per direction
+const DEVICE_HISTORY_LIMIT: usize = 20;
+// a default change this soon after a device appeared is the server's switch-on-connect, not the user
+const HOTPLUG_WINDOW: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum StreamType {
-    Microphones(SourceState), // Source
-    App(String), // Sink output with application ID
+    Microphones(SourceState, u32), // Source with base volume
+    App(AppInfo), // Sink input of an application
     Speaker(SinkState, u32), // Sink with base volume
-    Recording(String), // Source input with optional application name
+    Recording(AppInfo), // Source output of an application
 }
 
+// the application behind a playback or recording stream
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AppInfo {
+    pub name: String,
+    pub binary: String,
+    pub pid: Option<u32>,
+    pub media_title: String,
+    pub media_role: String,
+    pub corked: bool, // paused
+    pub device: u32, // index of the sink or source the stream is connected to
+}
 
-#[derive(Clone, Debug)]
+// a jack or connector of a card, sink or source
+#[derive(Clone, Debug, PartialEq)]
+pub struct Port {
+    pub name: String,
+    pub description: String,
+    pub priority: u32,
+    pub available: PortAvailable, // Unknown if the port has no jack detection
+    pub output: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CardProfile {
+    pub name: String,
+    pub description: String, // e.g. "High Fidelity Playback (A2DP Sink)"
+    pub priority: u32,
+    pub available: bool,
+    pub sinks: u32,
+    pub sources: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Card {
+    pub id: u32,
+    pub name: String,
+    pub description: String,
+    pub icon_name: String,
+    pub profiles: Vec<CardProfile>,
+    pub active_profile: Option<String>,
+    pub ports: Vec<Port>,
+}
+
+// broadcast when the availability of a card port changed, e.g. headphones were plugged in
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortChange {
+    pub card: String,
+    pub port: Port,
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct StreamEntry {
-    name: String,
-    description: String,
-    is_muted: bool,
-    volume: Vec<u32>,
-    icon_name: String,
-    type_: StreamType,
-    id: u32
+    pub name: String,
+    pub description: String,
+    pub is_muted: bool,
+    pub volume: Vec<u32>,
+    pub channel_map: ChannelMap,
+    pub icon_name: String,
+    pub type_: StreamType,
+    pub id: u32,
+    pub ports: Vec<Port>, // only for sinks and sources
+    pub active_port: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AudioData {
+    pub speakers: Vec<StreamEntry>,
+    pub microphones: Vec<StreamEntry>,
+    pub applications: Vec<StreamEntry>,
+    pub recorders: Vec<StreamEntry>,
+    pub cards: Vec<Card>,
+    pub default_sink: String,
+    pub default_source: String,
+}
+
+// a raw subscription event, the data is already updated when it's broadcast
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioEvent {
+    pub facility: Facility,
+    pub operation: Option<SubscribeOperation>,
+    pub index: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct AudioSender {
+    pub changed: Sender<Arc<RwLock<AudioData>>>,
+    pub event: Sender<AudioEvent>,
+    pub speakers: Sender<Arc<RwLock<AudioData>>>,
+    pub microphones: Sender<Arc<RwLock<AudioData>>>,
+    pub applications: Sender<Arc<RwLock<AudioData>>>,
+    pub recorders: Sender<Arc<RwLock<AudioData>>>,
+    pub cards: Sender<Arc<RwLock<AudioData>>>,
+    pub port_available: Sender<PortChange>,
+    pub default_sink: Sender<Arc<RwLock<AudioData>>>,
+    pub default_source: Sender<Arc<RwLock<AudioData>>>,
+    pub defaults: Sender<(String, String)>, // (source, sink) whenever either changed
 }
 
-impl<'a> From<&SinkInfo<'a>> for StreamEntry {
-    fn from(value: &SinkInfo) -> Self {
-        let base_volume = value.base_volume.0;
-        let channel_volumes = value.volume.get().iter().map(|x| x.0).collect::<Vec<_>>();
-        let icon_name = value.proplist.get_str("device.icon_name").unwrap_or("audio-card-analog-pci".into());
+impl AudioSender {
+    fn new() -> Self {
         Self {
-            name: String::from(value.name.as_ref().unwrap().deref()),
-            description: String::from(value.description.as_ref().unwrap().deref()),
-            is_muted: value.mute,
-            type_: StreamType::Speaker(value.state, base_volume),
-            volume: channel_volumes,
-            icon_name,
-            id: value.index
+            changed: channel(30).0,
+            event: channel(30).0,
+            speakers: channel(30).0,
+            microphones: channel(30).0,
+            applications: channel(30).0,
+            recorders: channel(30).0,
+            cards: channel(30).0,
+            port_available: channel(30).0,
+            default_sink: channel(30).0,
+            default_source: channel(30).0,
+            defaults: channel(30).0,
         }
     }
 }
 
-impl<'a> From<&SinkInputInfo<'a>> for StreamEntry {
-    fn from(value: &SinkInputInfo) -> Self {
-        let channel_volumes = value.volume.get().iter().map(|x| x.0).collect::<Vec<_>>();
-        let icon_name = value.proplist.get_str("device.icon_name").unwrap_or("audio-card-analog-pci".into());
-        let appname = value.proplist.get_str("application.name");
-        let name = String::from(value.name.as_ref().unwrap().deref());
-        Self {
-            description: name.clone(),
-            name,
-            is_muted: value.mute,
-            type_: StreamType::App(appname.unwrap_or("unknown".into())),
-            volume: channel_volumes,
-            icon_name,
-            id: value.index
+impl StreamEntry {
+    // 100% is the base volume for devices, the normal volume for streams
+    pub fn base_volume(&self) -> u32 {
+        match self.type_ {
+            StreamType::Speaker(_, base) | StreamType::Microphones(_, base) if base > Volume::MUTED.0 => base,
+            _ => Volume::NORMAL.0,
         }
     }
+
+    // loudest channel in percent of the base volume
+    pub fn percent(&self) -> f64 {
+        let max = self.volume.iter().copied().max().unwrap_or(0);
+        max as f64 * 100. / self.base_volume() as f64
+    }
+
+    pub fn channel_percents(&self) -> Vec<f64> {
+        self.volume.iter().map(|x| *x as f64 * 100. / self.base_volume() as f64).collect()
+    }
+
+    // -1 is fully left, 1 fully right
+    pub fn balance(&self) -> f32 {
+        self.channel_volumes().get_balance(&self.channel_map)
+    }
+
+    pub fn target(&self) -> AudioTarget {
+        match self.type_ {
+            StreamType::Speaker(..) => AudioTarget::Speaker(self.name.clone()),
+            StreamType::Microphones(..) => AudioTarget::Microphone(self.name.clone()),
+            StreamType::App(_) => AudioTarget::App(self.id),
+            StreamType::Recording(_) => AudioTarget::Recording(self.id),
+        }
+    }
+
+    fn channel_volumes(&self) -> ChannelVolumes {
+        let mut volumes = ChannelVolumes::default();
+        volumes.set_len(self.volume.len() as u8);
+        for (v, x) in volumes.get_mut().iter_mut().zip(&self.volume) {
+            *v = Volume(*x);
+        }
+        volumes
+    }
+
+    fn to_volume(&self, percent: f64, max_percent: f64) -> Volume {
+        let percent = percent.clamp(0., max_percent);
+        Volume(((self.base_volume() as f64 * percent / 100.).round() as u32).min(Volume::MAX.0))
+    }
+}
+
+// devices are addressed by name, streams by index
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AudioTarget {
+    Speaker(String),
+    Microphone(String),
+    App(u32),
+    Recording(u32),
+}
+
+// a sound server the service can talk to, volumes are always on the PulseAudio scale
+pub trait AudioBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn subscribe(&self) -> BoxFuture<'_, Result<UnboundedReceiver<AudioEvent>, AudioServiceError>>;
+    fn speakers(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>>;
+    fn microphones(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>>;
+    fn applications(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>>;
+    fn recorders(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>>;
+    fn cards(&self) -> BoxFuture<'_, Result<Vec<Card>, AudioServiceError>>;
+    fn defaults(&self) -> BoxFuture<'_, Result<(String, String), AudioServiceError>>; // (source, sink)
+    fn set_default(&self, target: AudioTarget) -> BoxFuture<'_, Result<(), AudioServiceError>>;
+    fn set_mute(&self, target: AudioTarget, yes: bool) -> BoxFuture<'_, Result<(), AudioServiceError>>;
+    fn set_volume(&self, target: AudioTarget, volumes: ChannelVolumes) -> BoxFuture<'_, Result<(), AudioServiceError>>;
+    fn set_card_profile(&self, card_name: String, profile: String) -> BoxFuture<'_, Result<(), AudioServiceError>>;
+    fn set_port(&self, target: AudioTarget, port: String) -> BoxFuture<'_, Result<(), AudioServiceError>>;
+    fn move_stream(&self, target: AudioTarget, device: String) -> BoxFuture<'_, Result<(), AudioServiceError>>;
+    // a mono record stream of a source or of a sink's monitor, samples are sent until the meter is closed
+    fn open_meter(&self, target: AudioTarget, rate: u32, peaks: bool, samples: UnboundedSender<Vec<f32>>) -> BoxFuture<'_, Result<u32, AudioServiceError>>;
+    fn close_meter(&self, id: u32) -> BoxFuture<'_, Result<(), AudioServiceError>>;
 }
 
-impl<'a> From<&SourceInfo<'a>> for StreamEntry {
-    fn from(value: &SourceInfo) -> Self {
-        let base_volume = value.base_volume.0;
-        let channel_volumes = value.volume.get().iter().map(|x| x.0).collect::<Vec<_>>();
-        let icon_name = value.proplist.get_str("device.icon_name").unwrap_or("audio-card-analog-pci".into());
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioLevel {
+    pub target: AudioTarget,
+    pub peak: f32, // linear, 0 to 1
+    pub bands: Vec<f32>, // 0 to 1 from low to high frequencies, empty without a spectrum
+}
+
+// the open meters by target and number of bands
+type Meters = Arc<Mutex<HashMap<(AudioTarget, usize), Sender<AudioLevel>>>>;
+
+// cava-like bars over the latest samples
+struct Spectrum {
+    fft: Arc<dyn Fft<f32>>,
+    samples: VecDeque<f32>,
+    window: Vec<f32>,
+    bars: Vec<f32>,
+}
+
+impl Spectrum {
+    fn new(bands: usize) -> Self {
+        let window = (0..FFT_SIZE)
+            .map(|i| 0.5 - 0.5 * (2. * std::f32::consts::PI * i as f32 / FFT_SIZE as f32).cos())
+            .collect();
         Self {
-            name: String::from(value.name.as_ref().unwrap().deref()),
-            description: String::from(value.description.as_ref().unwrap().deref()),
-            is_muted: value.mute,
-            type_: StreamType::Microphones(value.state),
-            volume: channel_volumes,
-            icon_name,
-            id: value.index
+            fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+            samples: VecDeque::with_capacity(FFT_SIZE),
+            window,
+            bars: vec![0.; bands],
+        }
+    }
+
+    fn push(&mut self, chunk: &[f32]) {
+        self.samples.extend(chunk);
+        let excess = self.samples.len().saturating_sub(FFT_SIZE);
+        self.samples.drain(..excess);
+    }
+
+    // log spaced bands from 50Hz to 10kHz, in dB mapped to 0..1
+    fn bands(&mut self) -> Vec<f32> {
+        if self.samples.len() < FFT_SIZE {
+            return self.bars.clone()
         }
+        let mut buffer = self.samples.iter()
+            .zip(&self.window)
+            .map(|(x, w)| Complex::new(x * w, 0.))
+            .collect::<Vec<_>>();
+        self.fft.process(&mut buffer);
+
+        let (low, high) = (50f32, 10000f32);
+        let n = self.bars.len() as f32;
+        let bin = |f: f32| ((f * FFT_SIZE as f32 / SPECTRUM_RATE as f32) as usize).clamp(1, FFT_SIZE / 2 - 1);
+        for (i, bar) in self.bars.iter_mut().enumerate() {
+            let from = bin(low * (high / low).powf(i as f32 / n));
+            let to = bin(low * (high / low).powf((i + 1) as f32 / n)).max(from + 1);
+            // the window halves the amplitude and only one side of the spectrum is used
+            let magnitude = buffer[from..to].iter().map(|x| x.norm()).fold(0., f32::max) * 4. / FFT_SIZE as f32;
+            let db = 20. * magnitude.max(1e-6).log10();
+            *bar = ((db + 60.) / 60.).clamp(0., 1.).max(*bar * BAR_FALLOFF);
+        }
+        self.bars.clone()
     }
 }
 
-impl<'a> From<&SourceOutputInfo<'a>> for StreamEntry {
-    fn from(value: &SourceOutputInfo) -> Self {
-        let channel_volumes = value.volume.get().iter().map(|x| x.0).collect::<Vec<_>>();
-        let icon_name = value.proplist.get_str("device.icon_name").unwrap_or("record".into());
-        let appname = value.proplist.get_str("application.name");
-        let name = String::from(value.name.as_ref().unwrap().deref());
-        let description = value.proplist.get_str("device.description").unwrap_or(name.clone());
+// EKSLISTENCE_AUDIO=pulse|pipewire forces a backend, otherwise PulseAudio
+// (or pipewire-pulse) is preferred and plain PipeWire is the fallback
+pub async fn from_env() -> Result<Arc<dyn AudioBackend>, AudioServiceError> {
+    match env::var("EKSLISTENCE_AUDIO").as_deref() {
+        Ok("pulse") => return Ok(Arc::new(PulseBackend::new().await?)),
+        Ok("pipewire") => return Ok(Arc::new(PipewireBackend::new().await?)),
+        _ => {}
+    }
+    match PulseBackend::new().await {
+        Ok(backend) => Ok(Arc::new(backend)),
+        Err(e) => {
+            warn!(target: "audio", "no PulseAudio server ({:?}), trying PipeWire", e);
+            Ok(Arc::new(PipewireBackend::new().await?))
+        }
+    }
+}
+
+// how the defaults follow hotplugged devices, read from $XDG_CONFIG_HOME/ekslistence/audio_policy.json
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DevicePolicy {
+    pub enabled: bool,
+    pub switch_on_connect: bool, // a new device becomes the default if it ranks above the current one
+    pub speakers: Vec<String>, // case insensitive parts of the name or description, highest priority first
+    pub microphones: Vec<String>,
+}
+
+impl Default for DevicePolicy {
+    fn default() -> Self {
         Self {
-            name,
-            description,
-            is_muted: value.mute,
-            type_: StreamType::Recording(appname.unwrap_or("unknown".into())),
-            volume: channel_volumes,
-            icon_name,
-            id: value.index
+            enabled: true,
+            switch_on_connect: true,
+            speakers: Vec::new(),
+            microphones: Vec::new(),
+        }
+    }
+}
+
+impl DevicePolicy {
+    pub fn path() -> std::io::Result<PathBuf> {
+        let mut path = PathGetter::config().map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        path.push("ekslistence");
+        std::fs::create_dir_all(&path)?;
+        path.push("audio_policy.json");
+        Ok(path)
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default())
+        }
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    // the first matching pattern, unlisted devices come last
+    fn priority(patterns: &[String], device: &StreamEntry) -> usize {
+        let name = device.name.to_lowercase();
+        let description = device.description.to_lowercase();
+        patterns.iter()
+            .map(|x| x.to_lowercase())
+            .position(|x| name.contains(&x) || description.contains(&x))
+            .unwrap_or(usize::MAX)
+    }
+
+    // manual choices rank above the priority list, the most recent one first
+    fn rank(patterns: &[String], history: &[String], device: &StreamEntry) -> (usize, usize) {
+        (history.iter().position(|x| *x == device.name).unwrap_or(usize::MAX), Self::priority(patterns, device))
+    }
+}
+
+fn state_path(file: &str) -> std::io::Result<PathBuf> {
+    let mut path = PathGetter::state().map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    path.push("ekslistence");
+    std::fs::create_dir_all(&path)?;
+    path.push(file);
+    Ok(path)
+}
+
+// a missing or broken file starts over
+fn load_state<T: DeserializeOwned + Default>(file: &str) -> T {
+    let state = state_path(file).and_then(|path| {
+        if !path.exists() {
+            return Ok(T::default())
         }
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    });
+    state.unwrap_or_else(|e| {
+        warn!(target: "audio", "couldn't read {}: {:?}", file, e);
+        T::default()
+    })
+}
+
+fn save_state<T: Serialize>(file: &str, value: &T) -> std::io::Result<()> {
+    let path = state_path(file)?;
+    // written to a temporary file first, so a crash doesn't leave a truncated file
+    let tmp = path.with_extension("json.tmp");
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    serde_json::to_writer(&mut writer, value)?;
+    std::io::Write::flush(&mut writer)?;
+    std::fs::rename(tmp, path)
+}
+
+// the defaults the user picked, most recent first, kept in $XDG_STATE_HOME/ekslistence/audio_devices.json
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct DeviceHistory {
+    speakers: Vec<String>,
+    microphones: Vec<String>,
+}
+
+impl DeviceHistory {
+    fn load() -> Self {
+        load_state("audio_devices.json")
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        save_state("audio_devices.json", self)
+    }
+
+    fn remember(history: &mut Vec<String>, name: &str) {
+        history.retain(|x| x != name);
+        history.insert(0, name.to_string());
+        history.truncate(DEVICE_HISTORY_LIMIT);
     }
 }
 
+// the last volume of an application's playback, kept in $XDG_STATE_HOME/ekslistence/app_volumes.json
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct AppVolume {
+    volume: Vec<u32>,
+    is_muted: bool,
+}
+
 pub struct AudioService {
-    mainloop: Rc<RefCell<Mainloop>>,
-    context: Rc<RefCell<Context>>,
+    pub data: Arc<RwLock<AudioData>>,
+    pub sender: AudioSender,
+    pub max_volume: f64, // in percent, e.g. 150 to allow over-amplification
+    pub move_streams_on_default: bool, // follow default device changes with all streams
+    pub meter_interval: Duration, // minimum time between two levels of a meter
+    pub policy: DevicePolicy, // reloaded whenever audio_policy.json changes
+    pub restore_app_volumes: bool, // reapply the last volume and mute of an application to its new streams
+    pub app_volume_roles: bool, // remember streams with a media role (e.g. "phone") separately
+    backend: Arc<dyn AudioBackend>,
+    meters: Meters, // a meter removes itself once it's closed
+    device_history: DeviceHistory,
+    connected: HashMap<String, Instant>, // devices that appeared within the hotplug window
+    pending_sink: Option<String>, // defaults we set ourselves, so they aren't taken as manual choices
+    pending_source: Option<String>,
+    policy_watcher: Option<notify::RecommendedWatcher>, // keeps the live reload running
+    app_volumes: HashMap<String, AppVolume>, // by application name, or "name|role"
+    unrestored: HashSet<u32>, // new streams that didn't have a volume to restore yet
 }
 
 #[derive(Debug, Clone)]
 pub enum AudioServiceError {
     NewMainloopError,
     NewContextError,
-    ConnectContextError(pulse::error::PAErr),
-    IterateError,
+    ConnectContextError(PAErr),
+    StartMainloopError(PAErr),
     ContextTerminatedError,
+    OperationError, // the sound server reported a failure
+    ThreadError, // the backend thread is gone
+    PipewireError(String),
 }
 
+impl AudioService {
+    pub async fn new() -> Result<Arc<RwLock<Self>>, AudioServiceError> {
+        Self::with_backend(from_env().await?).await
+    }
 
-use pulse::context::introspect::{ServerInfo, SinkInfo, SourceInfo};
-use std::cell::RefCell;
-use std::rc::Rc;
-
+    pub async fn with_backend(backend: Arc<dyn AudioBackend>) -> Result<Arc<RwLock<Self>>, AudioServiceError> {
+        debug!(target: "audio", "using the {} backend", backend.name());
+        let service = Arc::new(RwLock::new(Self {
+            data: Arc::new(RwLock::new(AudioData::default())),
+            sender: AudioSender::new(),
+            max_volume: 100.,
+            move_streams_on_default: false,
+            meter_interval: Duration::from_millis(1000 / PEAK_RATE as u64),
+            policy: DevicePolicy::default(),
+            restore_app_volumes: true,
+            app_volume_roles: false,
+            backend,
+            meters: Arc::new(Mutex::new(HashMap::new())),
+            device_history: DeviceHistory::load(),
+            connected: HashMap::new(),
+            pending_sink: None,
+            pending_source: None,
+            policy_watcher: None,
+            app_volumes: load_state("app_volumes.json"),
+            unrestored: HashSet::new(),
+        }));
 
+        Self::watch_policy(&service).await;
+        let events = service.read().await.backend.subscribe().await?;
+        service.write().await.sync_all().await?;
+        {
+            let service = service.clone();
+            tokio::spawn(Self::listen(service, events));
+        }
 
-impl AudioService {
-    fn new_context() -> Result<(Rc<RefCell<Mainloop>>, Rc<RefCell<Context>>), AudioServiceError> {
-        let mut proplist = Proplist::new().unwrap();
-        proplist.set_str(pulse::proplist::properties::APPLICATION_NAME, "FooApp")
-            .unwrap();
+        Ok(service)
+    }
 
-        let mut mainloop = Mainloop::new().ok_or(AudioServiceError::NewMainloopError)?;
+    async fn watch_policy(service: &Arc<RwLock<Self>>) {
+        let path = match DevicePolicy::path() {
+            Ok(path) => path,
+            Err(e) => {
+                warn!(target: "audio", "the device policy won't be loaded: {:?}", e);
+                return
+            }
+        };
+        service.write().await.reload_policy(&path);
 
-        let mut context = Context::new_with_proplist(
-            &mainloop,
-            "FooAppContext",
-            &proplist
-            ).ok_or(AudioServiceError::NewContextError)?;
+        // the directory is watched, as the file itself might not exist yet
+        let Some(dir) = path.parent() else {
+            return
+        };
+        let (watcher, mut rx) = match async_file_watcher(dir).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(target: "audio", "the device policy won't be reloaded: {:?}", e);
+                return
+            }
+        };
+        service.write().await.policy_watcher = Some(watcher);
 
-        context.connect(None, ContextFlagSet::NOFLAGS, None)
-            .map_err(AudioServiceError::ConnectContextError)?;
+        let service = Arc::downgrade(service);
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if !event.paths.contains(&path) {
+                    continue
+                }
+                match service.upgrade() {
+                    Some(s) => s.write().await.reload_policy(&path),
+                    None => return
+                }
+            }
+        });
+    }
 
-        // Wait for context to be ready
-        loop {
-            match mainloop.iterate(false) {
-                IterateResult::Quit(_) |
-                IterateResult::Err(_) => {
-                    eprintln!("Iterate state was not success, quitting...");
-                    return Err(AudioServiceError::IterateError);
-                },
-                IterateResult::Success(_) => {},
-            }
-            match context.get_state() {
-                pulse::context::State::Ready => { break; },
-                pulse::context::State::Failed |
-                pulse::context::State::Terminated => {
-                    eprintln!("Context state failed/terminated, quitting...");
-                    return Err(AudioServiceError::ContextTerminatedError);
-                },
-                _ => {},
+    // a broken policy file keeps the previous policy
+    fn reload_policy(&mut self, path: &Path) {
+        match DevicePolicy::load(path) {
+            Ok(policy) => {
+                info!(target: "audio", "loaded the device policy");
+                self.policy = policy;
             }
+            Err(e) => warn!(target: "audio", "couldn't load {:?}: {:?}", path, e),
         }
+    }
 
+    pub fn backend_name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    async fn listen(service: Arc<RwLock<Self>>, mut events: UnboundedReceiver<AudioEvent>) {
+        while let Some(event) = events.recv().await {
+            // events come in bursts (e.g. a new stream also changes its sink), so a facility is only synced once
+            let mut batch = vec![event];
+            while let Ok(event) = events.try_recv() {
+                batch.push(event);
+            }
+            let mut facilities = Vec::new();
+            for event in &batch {
+                if !facilities.contains(&event.facility) {
+                    facilities.push(event.facility);
+                }
+            }
 
+            let mut w = service.write().await;
+            let before = w.data.read().await.clone();
+            for facility in facilities {
+                if let Err(e) = w.sync(facility).await {
+                    warn!(target: "audio", "couldn't sync {:?}: {:?}", facility, e);
+                }
+            }
+            w.apply_policy(&before).await;
+            for event in batch.iter().filter(|x| x.facility == Facility::SinkInput) {
+                match event.operation {
+                    Some(SubscribeOperation::New) => { w.unrestored.insert(event.index); },
+                    Some(SubscribeOperation::Removed) => { w.unrestored.remove(&event.index); },
+                    _ => {}
+                }
+            }
+            w.restore_app_volumes().await;
+            for event in batch {
+                if w.sender.event.send(event).is_err() {
+                    debug!(target: "audio", "No receiver");
+                }
+            }
+            w.update().await;
+        }
+    }
 
-        Ok((Rc::new(RefCell::new(mainloop)), Rc::new(RefCell::new(context))))
+    async fn sync_all(&mut self) -> Result<(), AudioServiceError> {
+        for facility in [Facility::Card, Facility::SinkInput, Facility::SourceOutput, Facility::Server] {
+            self.sync(facility).await?;
+        }
+        self.update().await;
+        Ok(())
     }
 
-    fn wait_op<T: ?Sized>(&self, op: &Operation<T>) -> Result<(), AudioServiceError> {
-        while op.get_state() == State::Running {
-            match self.mainloop.borrow_mut().iterate(true) {
-                IterateResult::Quit(_) | IterateResult::Err(_) => {
-                    eprintln!("Iterate state was not success, quitting...");
-                    return Err(AudioServiceError::IterateError)
+    async fn sync(&mut self, facility: Facility) -> Result<(), AudioServiceError> {
+        match facility {
+            Facility::Sink => {
+                let speakers = self.get_speakers().await?;
+                self.update_speakers(speakers).await;
+            }
+            Facility::Source => {
+                let microphones = self.get_microphones().await?;
+                self.update_microphones(microphones).await;
+            }
+            Facility::SinkInput => {
+                let applications = self.get_applications().await?;
+                self.remember_app_volumes(&applications).await;
+                self.update_applications(applications).await;
+            }
+            Facility::SourceOutput => {
+                let recorders = self.get_recorders().await?;
+                self.update_recorders(recorders).await;
+            }
+            Facility::Server => {
+                let (default_source, default_sink) = self.get_defaults().await?;
+                let (source_changed, sink_changed) = {
+                    let data = self.data.read().await;
+                    (data.default_source != default_source, data.default_sink != default_sink)
+                };
+                if self.move_streams_on_default {
+                    if sink_changed {
+                        self.move_all_applications(&default_sink).await;
+                    }
+                    if source_changed {
+                        self.move_all_recorders(&default_source).await;
+                    }
+                }
+                if (source_changed || sink_changed) && self.sender.defaults.send((default_source.clone(), default_sink.clone())).is_err() {
+                    debug!(target: "audio", "No receiver");
                 }
-                IterateResult::Success(_) => {  }
+                self.update_default_source(default_source).await;
+                self.update_default_sink(default_sink).await;
             }
+            // profile changes replace the sinks and sources of the card
+            Facility::Card => {
+                let cards = self.get_cards().await?;
+                self.broadcast_port_changes(&cards).await;
+                self.update_cards(cards).await;
+                let speakers = self.get_speakers().await?;
+                self.update_speakers(speakers).await;
+                let microphones = self.get_microphones().await?;
+                self.update_microphones(microphones).await;
+            }
+            _ => {}
         }
         Ok(())
     }
 
-    pub fn get<G: ?Sized, F: FnOnce(Introspector, Rc<RefCell<Vec<StreamEntry>>>) -> Operation<G>>(&self, getter: F)  -> Result<Vec<StreamEntry>, AudioServiceError> {
-        let result = Rc::new(RefCell::new(Vec::new()));
-        {
-            let w = result.clone();
-            let op = getter(self.context.borrow_mut().introspect(), w);
+    // the server already picked a new default when one disappeared, the policy may override its choice
+    async fn apply_policy(&mut self, before: &AudioData) {
+        if !self.policy.enabled {
+            return
+        }
+        let now = Instant::now();
+        self.connected.retain(|_, time| now.duration_since(*time) < HOTPLUG_WINDOW);
+        let data = self.data.read().await.clone();
+        for (speakers, target) in [(true, self.policy_target(true, before, &data)), (false, self.policy_target(false, before, &data))] {
+            let Some(name) = target else {
+                continue
+            };
+            debug!(target: "audio", "policy switches the default {} to {}", if speakers { "sink" } else { "source" }, name);
+            let result = if speakers {
+                self.set_speaker(&name).await
+            } else {
+                self.set_microphone(&name).await
+            };
+            match result {
+                Ok(()) if speakers => self.pending_sink = Some(name),
+                Ok(()) => self.pending_source = Some(name),
+                Err(e) => warn!(target: "audio", "couldn't switch the default to {}: {:?}", name, e),
+            }
+        }
+    }
 
-            self.wait_op(&op)?;
+    // the device the default should switch to, if any
+    fn policy_target(&mut self, speakers: bool, before: &AudioData, data: &AudioData) -> Option<String> {
+        let (patterns, history, pending, old_devices, devices, old_default, default) = if speakers {
+            (&self.policy.speakers, &mut self.device_history.speakers, &mut self.pending_sink, &before.speakers, &data.speakers, &before.default_sink, &data.default_sink)
+        } else {
+            (&self.policy.microphones, &mut self.device_history.microphones, &mut self.pending_source, &before.microphones, &data.microphones, &before.default_source, &data.default_source)
+        };
+        let now = Instant::now();
+        let added: Vec<&StreamEntry> = devices.iter().filter(|x| !old_devices.iter().any(|y| y.name == x.name)).collect();
+        for device in &added {
+            self.connected.insert(device.name.clone(), now);
         }
-        
-        Ok(Rc::try_unwrap(result).unwrap().into_inner())
+
+        let changed = default != old_default;
+        // any change settles our own switch, even if something else overtook it
+        if changed && pending.take().as_ref() == Some(default) {
+            return None
+        }
+        if !old_default.is_empty() && !devices.iter().any(|x| x.name == *old_default) {
+            // the default is gone, rank what's left over the server's pick
+            let best = devices.iter()
+                .map(|x| (DevicePolicy::rank(patterns, history, x), x))
+                .filter(|(rank, _)| *rank != (usize::MAX, usize::MAX))
+                .min_by_key(|(rank, _)| *rank)?
+                .1;
+            return (best.name != *default).then(|| best.name.clone())
+        }
+        if changed && !default.is_empty() && !self.connected.contains_key(default) {
+            DeviceHistory::remember(history, default);
+            if let Err(e) = self.device_history.save() {
+                warn!(target: "audio", "couldn't save the device history: {:?}", e);
+            }
+            return None
+        }
+        if self.policy.switch_on_connect && !added.is_empty() {
+            // ties keep the current default
+            let best = devices.iter()
+                .filter(|x| x.name == *default)
+                .chain(added)
+                .min_by_key(|x| DevicePolicy::rank(patterns, history, x))?;
+            return (best.name != *default).then(|| best.name.clone())
+        }
+        None
     }
 
-    pub fn get_speakers(&self) -> Result<Vec<StreamEntry>, AudioServiceError> {
-        self.get(|x, w|
-            x.get_sink_info_list(
-            move |x| {
-                if let pulse::callbacks::ListResult::Item(e) = x {
-                    w.borrow_mut().push(e.into());
-                };
-            },
-        ))
+    fn app_volume_key(&self, app: &AppInfo) -> Option<String> {
+        if app.name.is_empty() {
+            return None
+        }
+        if self.app_volume_roles && !app.media_role.is_empty() {
+            return Some(format!("{}|{}", app.name, app.media_role))
+        }
+        Some(app.name.clone())
     }
 
+    // only changes of known streams count, a new stream starts with what the server restored
+    async fn remember_app_volumes(&mut self, applications: &[StreamEntry]) {
+        if !self.restore_app_volumes {
+            return
+        }
+        let old = self.data.read().await.applications.clone();
+        let mut changed = false;
+        for entry in applications {
+            let StreamType::App(app) = &entry.type_ else {
+                continue
+            };
+            let Some(before) = old.iter().find(|x| x.id == entry.id) else {
+                continue
+            };
+            // the first volume of a stream and the ones before restoring aren't choices of the user
+            if before.volume.is_empty() || entry.volume.is_empty() || self.unrestored.contains(&entry.id) {
+                continue
+            }
+            if before.volume == entry.volume && before.is_muted == entry.is_muted {
+                continue
+            }
+            let Some(key) = self.app_volume_key(app) else {
+                continue
+            };
+            self.app_volumes.insert(key, AppVolume { volume: entry.volume.clone(), is_muted: entry.is_muted });
+            changed = true;
+        }
+        if changed {
+            if let Err(e) = save_state("app_volumes.json", &self.app_volumes) {
+                warn!(target: "audio", "couldn't save the application volumes: {:?}", e);
+            }
+        }
+    }
 
-    pub fn get_microphones(&self) -> Result<Vec<StreamEntry>, AudioServiceError> {
-        self.get(|x, w|
-            x.get_source_info_list(
-            move |x| {
-                if let pulse::callbacks::ListResult::Item(e) = x {
-                    if e.proplist.get_str("device.class") == Some("sound".to_string()) {
-                        w.borrow_mut().push(e.into());
-                    }
-                };
-            },
-        ))
+    // a stream stays unrestored until its volume is known, the next change of it retries
+    async fn restore_app_volumes(&mut self) {
+        if !self.restore_app_volumes || self.unrestored.is_empty() {
+            return
+        }
+        let applications = self.data.read().await.applications.clone();
+        let ready = applications.iter()
+            .filter(|x| self.unrestored.contains(&x.id) && !x.volume.is_empty())
+            .collect::<Vec<_>>();
+        for entry in ready {
+            self.unrestored.remove(&entry.id);
+            let StreamType::App(app) = &entry.type_ else {
+                continue
+            };
+            // streams without an application name aren't remembered, so there's nothing to restore
+            let Some(key) = self.app_volume_key(app) else {
+                continue
+            };
+            // a role without its own entry falls back to the application
+            let Some(saved) = self.app_volumes.get(&key).or_else(|| self.app_volumes.get(&app.name)) else {
+                continue
+            };
+            let mut volumes = entry.channel_volumes();
+            if saved.volume.len() == entry.volume.len() {
+                for (v, x) in volumes.get_mut().iter_mut().zip(&saved.volume) {
+                    *v = Volume(*x);
+                }
+            } else if let Some(max) = saved.volume.iter().max() {
+                // different channel count, the balance can't be kept
+                volumes.set(entry.volume.len() as u8, Volume(*max));
+            }
+            debug!(target: "audio", "restoring the volume of {} on stream {}", app.name, entry.id);
+            if saved.volume != entry.volume {
+                if let Err(e) = self.apply_volume(entry, volumes).await {
+                    warn!(target: "audio", "couldn't restore the volume of {}: {:?}", app.name, e);
+                }
+            }
+            if saved.is_muted != entry.is_muted {
+                if let Err(e) = self.set_mute_application(entry.id, saved.is_muted).await {
+                    warn!(target: "audio", "couldn't restore the mute of {}: {:?}", app.name, e);
+                }
+            }
+        }
     }
 
+    update!(update_speakers, speakers, Vec<StreamEntry>);
+    update!(update_microphones, microphones, Vec<StreamEntry>);
+    update!(update_applications, applications, Vec<StreamEntry>);
+    update!(update_recorders, recorders, Vec<StreamEntry>);
+    update!(update_cards, cards, Vec<Card>);
+    update!(update_default_sink, default_sink, String);
+    update!(update_default_source, default_source, String);
 
-    pub fn get_recorders(&self) -> Result<Vec<StreamEntry>, AudioServiceError> {
-        self.get(|x, w|
-            x.get_source_output_info_list(
-            move |x| {
-                println!("{:?}", x);
-                if let pulse::callbacks::ListResult::Item(e) = x {
-                    println!("{:?}", e);
-                    // w.borrow_mut().push(e.into());
-                };
-            },
-        ))
-    }
-
-    pub fn get_applications(&self) -> Result<Vec<StreamEntry>, AudioServiceError> {
-        self.get(|x, w|
-            x.get_sink_input_info_list(
-            move |x| {
-                println!("{:?}", x);
-                if let pulse::callbacks::ListResult::Item(e) = x {
-                    println!("{:?}", e);
-                    // w.borrow_mut().push(e.into());
-                };
-            },
-        ))
+    // only ports that were known before, so there's nothing on startup
+    async fn broadcast_port_changes(&self, cards: &[Card]) {
+        let data = self.data.read().await;
+        for card in cards {
+            let Some(old) = data.cards.iter().find(|x| x.name == card.name) else {
+                continue
+            };
+            for port in &card.ports {
+                let changed = old.ports.iter().any(|x| x.name == port.name && x.available != port.available);
+                if changed && self.sender.port_available.send(PortChange { card: card.name.clone(), port: port.clone() }).is_err() {
+                    debug!(target: "audio", "No receiver");
+                }
+            }
+        }
     }
 
-    pub fn get_defaults(&self) -> Result<(String, String), AudioServiceError> {
-        let sink_source = Rc::new(RefCell::new((String::new(), String::new())));
+    async fn update(&mut self) {
+        match self.sender.changed.send(self.data.clone()) {
+            Ok(_) => {},
+            Err(_) => {debug!(target: "audio", "No receiver");}
+        }
+    }
 
+    pub async fn get_speakers(&self) -> Result<Vec<StreamEntry>, AudioServiceError> {
+        self.backend.speakers().await
+    }
 
-        {
-            let sink_source_clone = sink_source.clone();
-            let op = self
-                .context
-                .borrow()
-                .introspect()
-                .get_server_info(move |x: &ServerInfo| {
-                    let source_name = x.default_source_name.as_ref().unwrap().deref();
-                    let sink_name = x.default_sink_name.as_ref().unwrap().deref();
-                    *sink_source_clone.borrow_mut() = (String::from(source_name), String::from(sink_name));
-                });
-            self.wait_op(&op);
+    pub async fn get_microphones(&self) -> Result<Vec<StreamEntry>, AudioServiceError> {
+        self.backend.microphones().await
+    }
+
+    pub async fn get_recorders(&self) -> Result<Vec<StreamEntry>, AudioServiceError> {
+        self.backend.recorders().await
+    }
+
+    pub async fn get_applications(&self) -> Result<Vec<StreamEntry>, AudioServiceError> {
+        self.backend.applications().await
+    }
+
+    pub async fn get_cards(&self) -> Result<Vec<Card>, AudioServiceError> {
+        self.backend.cards().await
+    }
+
+    // (source, sink)
+    pub async fn get_defaults(&self) -> Result<(String, String), AudioServiceError> {
+        self.backend.defaults().await
+    }
+
+    pub async fn set_microphone(&self, mic: &str) -> Result<(), AudioServiceError> {
+        self.backend.set_default(AudioTarget::Microphone(mic.to_string())).await
+    }
+
+    pub async fn set_speaker(&self, speaker: &str) -> Result<(), AudioServiceError> {
+        self.backend.set_default(AudioTarget::Speaker(speaker.to_string())).await
+    }
+
+    pub async fn set_mute_microphone(&self, mic: &str, yes: bool) -> Result<(), AudioServiceError> {
+        self.backend.set_mute(AudioTarget::Microphone(mic.to_string()), yes).await
+    }
+
+    pub async fn set_mute_speaker(&self, speaker: &str, yes: bool) -> Result<(), AudioServiceError> {
+        self.backend.set_mute(AudioTarget::Speaker(speaker.to_string()), yes).await
+    }
+
+    pub async fn set_mute_application(&self, app: u32, yes: bool) -> Result<(), AudioServiceError> {
+        self.backend.set_mute(AudioTarget::App(app), yes).await
+    }
+
+    // keeps the balance between the channels
+    pub async fn set_volume(&self, entry: &StreamEntry, percent: f64) -> Result<(), AudioServiceError> {
+        let mut volumes = entry.channel_volumes();
+        volumes.scale(entry.to_volume(percent, self.max_volume));
+        self.apply_volume(entry, volumes).await
+    }
+
+    // relative to the current volume, e.g. -5 for a scroll step down
+    pub async fn change_volume(&self, entry: &StreamEntry, delta: f64) -> Result<(), AudioServiceError> {
+        self.set_volume(entry, entry.percent() + delta).await
+    }
+
+    // one percentage per channel, in the order of the channel map
+    pub async fn set_channel_volumes(&self, entry: &StreamEntry, percents: &[f64]) -> Result<(), AudioServiceError> {
+        let mut volumes = entry.channel_volumes();
+        for (v, percent) in volumes.get_mut().iter_mut().zip(percents) {
+            *v = entry.to_volume(*percent, self.max_volume);
         }
+        self.apply_volume(entry, volumes).await
+    }
 
-        Ok(Rc::try_unwrap(sink_source).unwrap().into_inner())
+    pub async fn set_balance(&self, entry: &StreamEntry, balance: f32) -> Result<(), AudioServiceError> {
+        let mut volumes = entry.channel_volumes();
+        volumes
+            .set_balance(&entry.channel_map, balance.clamp(-1., 1.))
+            .ok_or(AudioServiceError::OperationError)?;
+        self.apply_volume(entry, volumes).await
     }
 
-    pub fn set_microphone(&self, mic: &str) -> Result<(), AudioServiceError> {
-        let op = self.context.borrow_mut().set_default_source(mic, |_| ());
-        self.wait_op(&op)
+    async fn apply_volume(&self, entry: &StreamEntry, volumes: ChannelVolumes) -> Result<(), AudioServiceError> {
+        self.backend.set_volume(entry.target(), volumes).await
     }
 
-    pub fn set_speaker(&self, speaker: &str) -> Result<(), AudioServiceError> {
-        let op = self.context.borrow_mut().set_default_sink(speaker, |_| ());
-        self.wait_op(&op)
+    pub async fn set_card_profile(&self, card_name: &str, profile: &str) -> Result<(), AudioServiceError> {
+        self.backend.set_card_profile(card_name.to_string(), profile.to_string()).await
     }
 
-    pub fn set_mute_microphone(&self, mic: &str, yes: bool) ->  Result<(), AudioServiceError> {
-        let op = self.context.borrow_mut().introspect().set_source_mute_by_name(mic, yes, None);
-        self.wait_op(&op)
+    // only sinks and sources have ports
+    pub async fn set_port(&self, entry: &StreamEntry, port: &str) -> Result<(), AudioServiceError> {
+        self.backend.set_port(entry.target(), port.to_string()).await
     }
 
-    pub fn set_mute_speaker(&self, speaker: &str, yes: bool) ->  Result<(), AudioServiceError> {
-        let op = self.context.borrow_mut().introspect().set_sink_mute_by_name(speaker, yes, None);
-        self.wait_op(&op)
+    pub async fn move_application(&self, stream_id: u32, sink_name: &str) -> Result<(), AudioServiceError> {
+        self.backend.move_stream(AudioTarget::App(stream_id), sink_name.to_string()).await
     }
 
-    pub fn set_mute_application(&self, app: u32, yes: bool) ->  Result<(), AudioServiceError> {
-        let op = self.context.borrow_mut().introspect().set_sink_input_mute(app, yes, None);
-        self.wait_op(&op)
+    pub async fn move_recorder(&self, stream_id: u32, source_name: &str) -> Result<(), AudioServiceError> {
+        self.backend.move_stream(AudioTarget::Recording(stream_id), source_name.to_string()).await
     }
 
+    async fn move_all_applications(&self, sink_name: &str) {
+        let (sink, streams) = {
+            let data = self.data.read().await;
+            let sink = data.speakers.iter().find(|x| x.name == sink_name).map(|x| x.id);
+            (sink, data.applications.clone())
+        };
+        for stream in streams {
+            if let StreamType::App(app) = &stream.type_ {
+                // a stream that vanished in between shouldn't stop the others
+                if Some(app.device) != sink {
+                    if let Err(e) = self.move_application(stream.id, sink_name).await {
+                        warn!(target: "audio", "couldn't move stream {}: {:?}", stream.id, e);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn move_all_recorders(&self, source_name: &str) {
+        let (source, streams) = {
+            let data = self.data.read().await;
+            let source = data.microphones.iter().find(|x| x.name == source_name).map(|x| x.id);
+            (source, data.recorders.clone())
+        };
+        for stream in streams {
+            if let StreamType::Recording(app) = &stream.type_ {
+                if Some(app.device) != source {
+                    if let Err(e) = self.move_recorder(stream.id, source_name).await {
+                        warn!(target: "audio", "couldn't move stream {}: {:?}", stream.id, e);
+                    }
+                }
+            }
+        }
+    }
 
-    pub fn set_mute_recorder(&self, rec: u32, yes: bool) ->  Result<(), AudioServiceError> {
-        let op = self.context.borrow_mut().introspect().set_source_output_mute(rec, yes, None);
-        self.wait_op(&op)
+    pub async fn set_mute_recorder(&self, rec: u32, yes: bool) -> Result<(), AudioServiceError> {
+        self.backend.set_mute(AudioTarget::Recording(rec), yes).await
     }
 
+    // levels of a source or of what a sink plays, bands > 0 adds a spectrum with that many bars;
+    // the stream is opened for the first receiver and closed once all receivers are dropped
+    pub async fn meter(&mut self, target: AudioTarget, bands: usize) -> Result<Receiver<AudioLevel>, AudioServiceError> {
+        let key = (target.clone(), bands);
+        if let Some(sender) = self.meters.lock().await.get(&key) {
+            // a meter without receivers is about to close
+            if sender.receiver_count() > 0 {
+                return Ok(sender.subscribe())
+            }
+        }
+
+        // a spectrum needs the actual samples, the level alone only peaks
+        let peaks = bands == 0;
+        let rate = if peaks { PEAK_RATE } else { SPECTRUM_RATE };
+        let (samples_tx, samples) = unbounded_channel();
+        let id = self.backend.open_meter(target.clone(), rate, peaks, samples_tx).await?;
 
-    pub fn new() -> Result<Self, AudioServiceError> {
-        let (mainloop, context) = Self::new_context()?;
-        Ok(Self {mainloop, context})
+        let (sender, receiver) = channel(30);
+        self.meters.lock().await.insert(key.clone(), sender.clone());
+        tokio::spawn(Self::run_meter(
+            self.backend.clone(),
+            self.meters.clone(),
+            id,
+            key,
+            samples,
+            sender,
+            self.meter_interval,
+        ));
+        Ok(receiver)
     }
-}
 
-impl Drop for AudioService {
-    fn drop(&mut self) {
-        self.context.borrow_mut().disconnect();
+    async fn run_meter(
+        backend: Arc<dyn AudioBackend>,
+        meters: Meters,
+        id: u32,
+        key: (AudioTarget, usize),
+        mut samples: UnboundedReceiver<Vec<f32>>,
+        sender: Sender<AudioLevel>,
+        interval: Duration,
+    ) {
+        let target = key.0.clone();
+        let mut spectrum = (key.1 > 0).then(|| Spectrum::new(key.1));
+        let mut peak = 0f32;
+        let mut last = Instant::now();
+        // a suspended device sends no samples, so the receivers are also checked without them
+        let mut idle = tokio::time::interval(METER_IDLE_CHECK);
+        loop {
+            let chunk = tokio::select! {
+                chunk = samples.recv() => match chunk {
+                    Some(chunk) => chunk,
+                    // the stream failed, e.g. because the device is gone
+                    None => break,
+                },
+                _ = idle.tick() => {
+                    if sender.receiver_count() == 0 {
+                        break
+                    }
+                    continue
+                }
+            };
+            if sender.receiver_count() == 0 {
+                break
+            }
+            peak = chunk.iter().fold(peak, |p, x| p.max(x.abs()));
+            if let Some(spectrum) = &mut spectrum {
+                spectrum.push(&chunk);
+            }
+            if last.elapsed() < interval {
+                continue
+            }
+            last = Instant::now();
+            let level = AudioLevel {
+                target: target.clone(),
+                peak: peak.min(1.),
+                bands: spectrum.as_mut().map(Spectrum::bands).unwrap_or_default(),
+            };
+            peak = 0.;
+            if sender.send(level).is_err() {
+                break
+            }
+        }
+        {
+            // a new meter for the same target may have replaced this one already
+            let mut meters = meters.lock().await;
+            if meters.get(&key).is_some_and(|x| x.same_channel(&sender)) {
+                meters.remove(&key);
+            }
+        }
+        if let Err(e) = backend.close_meter(id).await {
+            debug!(target: "audio", "couldn't close meter {}: {:?}", id, e);
+        }
     }
 }
 
 
 // speaker: get_sink_info_list
 // microphone: get_source_info_list
-// apps: get_source_input_info_list
-// recorders: get_sink_input_info_list
+// apps: get_sink_input_info_list
+// recorders: get_source_output_info_list
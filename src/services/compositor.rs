@@ -0,0 +1,85 @@
+use std::env;
+use std::sync::Arc;
+
+use tokio::sync::{broadcast::Receiver, RwLock};
+
+use super::hyprland::HyprlandService;
+use super::sway::SwayService;
+use super::utils::BoxFuture;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompositorWorkspace {
+    pub id: i64, // workspace id on Hyprland, node id on Sway
+    pub name: String,
+    pub output: String,
+    pub focused: bool,
+    pub visible: bool,
+    pub urgent: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompositorWindow {
+    pub id: String, // address on Hyprland, container id on Sway
+    pub title: String,
+    pub app_id: String, // class on Hyprland/X11
+    pub pid: i64,
+    pub workspace: String,
+    pub focused: bool,
+    pub floating: bool,
+    pub fullscreen: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompositorOutput {
+    pub name: String,
+    pub description: String,
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+    pub scale: f64,
+    pub focused: bool,
+    pub active_workspace: String,
+}
+
+// Only tells what changed, the new state has to be read from the compositor
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CompositorEvent {
+    Workspaces,
+    Windows,
+    Outputs,
+    Focus,
+    Reloaded,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompositorCommand {
+    FocusWorkspace(String), // numeric names are treated as workspace numbers
+    MoveToWorkspace(String), // moves the focused window
+    FocusWindow(String),
+    CloseWindow(String),
+    FocusOutput(String),
+    ToggleFloating,
+    ToggleFullscreen,
+    Exec(String),
+}
+
+pub trait Compositor: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn workspaces(&self) -> BoxFuture<'_, std::io::Result<Vec<CompositorWorkspace>>>;
+    fn windows(&self) -> BoxFuture<'_, std::io::Result<Vec<CompositorWindow>>>;
+    fn outputs(&self) -> BoxFuture<'_, std::io::Result<Vec<CompositorOutput>>>;
+    fn focused_window(&self) -> BoxFuture<'_, std::io::Result<Option<CompositorWindow>>>;
+    fn command(&self, cmd: CompositorCommand) -> BoxFuture<'_, std::io::Result<()>>;
+    fn subscribe(&self) -> Receiver<CompositorEvent>;
+}
+
+// picks the backend of the running session, without either variable Hyprland keeps
+// looking for an instance and reports itself as absent meanwhile
+pub async fn from_env() -> std::io::Result<Arc<RwLock<dyn Compositor>>> {
+    if env::var("SWAYSOCK").is_ok() && env::var("HYPRLAND_INSTANCE_SIGNATURE").is_err() {
+        Ok(SwayService::new().await?)
+    } else {
+        Ok(HyprlandService::new().await?)
+    }
+}
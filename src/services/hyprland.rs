@@ -1,61 +1,705 @@
+use std::collections::HashMap;
 use std::env;
-use std::path::Path;
-use std::io;
+use std::fmt;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::net::{unix::SocketAddr, UnixListener, UnixStream};
-use tokio::io::Interest;
-use tokio::sync::RwLock;
+use log::{debug, warn};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use tokio::net::UnixStream;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast::{channel, Receiver, Sender}, RwLock};
+
+use super::compositor::{Compositor, CompositorCommand, CompositorEvent, CompositorOutput, CompositorWindow, CompositorWorkspace};
+use super::utils::BoxFuture;
+
+
+// Events as emitted on socket2, see https://wiki.hyprland.org/IPC/
+// Window addresses are normalized to the `0x…` form used by the JSON replies
+#[derive(Clone, Debug, PartialEq)]
+pub enum HyprlandEvent {
+    Workspace(String),
+    WorkspaceV2 { id: i64, name: String },
+    FocusedMon { monitor: String, workspace: String },
+    ActiveWindow { class: String, title: String },
+    ActiveWindowV2(Option<String>), // address, None if nothing is focused
+    Fullscreen(bool),
+    MonitorRemoved(String),
+    MonitorAdded(String),
+    MonitorAddedV2 { id: i64, name: String, description: String },
+    CreateWorkspace(String),
+    CreateWorkspaceV2 { id: i64, name: String },
+    DestroyWorkspace(String),
+    DestroyWorkspaceV2 { id: i64, name: String },
+    MoveWorkspace { workspace: String, monitor: String },
+    MoveWorkspaceV2 { id: i64, name: String, monitor: String },
+    RenameWorkspace { id: i64, name: String },
+    ActiveSpecial { workspace: String, monitor: String },
+    ActiveLayout { keyboard: String, layout: String },
+    OpenWindow { address: String, workspace: String, class: String, title: String },
+    CloseWindow(String),
+    MoveWindow { address: String, workspace: String },
+    MoveWindowV2 { address: String, workspace_id: i64, workspace: String },
+    OpenLayer(String),
+    CloseLayer(String),
+    Submap(String),
+    ChangeFloatingMode { address: String, floating: bool },
+    Urgent(String),
+    Minimize { address: String, minimized: bool },
+    Screencast { active: bool, owner: u8 }, // owner: 0 = monitor, 1 = window
+    WindowTitle(String),
+    WindowTitleV2 { address: String, title: String },
+    ToggleGroup { open: bool, addresses: Vec<String> },
+    MoveIntoGroup(String),
+    MoveOutOfGroup(String),
+    IgnoreGroupLock(bool),
+    LockGroups(bool),
+    Pin { address: String, pinned: bool },
+    ConfigReloaded,
+    Unknown { name: String, data: String },
+}
+
+fn address(s: &str) -> String {
+    if s.starts_with("0x") { s.to_string() } else { format!("0x{s}") }
+}
+
+fn id(s: &str) -> i64 {
+    s.parse().unwrap_or(-1)
+}
+
+impl HyprlandEvent {
+    // the last argument may contain commas (titles, classes), so it is never split further
+    fn args(data: &str, n: usize) -> Vec<&str> {
+        let mut v = data.splitn(n, ',').collect::<Vec<_>>();
+        v.resize(n, "");
+        v
+    }
+
+    pub fn parse(line: &str) -> Self {
+        let (name, data) = line.split_once(">>").unwrap_or((line, ""));
+        match name {
+            "workspace" => HyprlandEvent::Workspace(data.into()),
+            "workspacev2" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::WorkspaceV2 { id: id(a[0]), name: a[1].into() }
+            }
+            "focusedmon" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::FocusedMon { monitor: a[0].into(), workspace: a[1].into() }
+            }
+            "activewindow" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::ActiveWindow { class: a[0].into(), title: a[1].into() }
+            }
+            "activewindowv2" => HyprlandEvent::ActiveWindowV2(
+                if data.is_empty() || data == "," { None } else { Some(address(data)) }
+            ),
+            "fullscreen" => HyprlandEvent::Fullscreen(data == "1"),
+            "monitorremoved" => HyprlandEvent::MonitorRemoved(data.into()),
+            "monitoradded" => HyprlandEvent::MonitorAdded(data.into()),
+            "monitoraddedv2" => {
+                let a = Self::args(data, 3);
+                HyprlandEvent::MonitorAddedV2 { id: id(a[0]), name: a[1].into(), description: a[2].into() }
+            }
+            "createworkspace" => HyprlandEvent::CreateWorkspace(data.into()),
+            "createworkspacev2" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::CreateWorkspaceV2 { id: id(a[0]), name: a[1].into() }
+            }
+            "destroyworkspace" => HyprlandEvent::DestroyWorkspace(data.into()),
+            "destroyworkspacev2" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::DestroyWorkspaceV2 { id: id(a[0]), name: a[1].into() }
+            }
+            "moveworkspace" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::MoveWorkspace { workspace: a[0].into(), monitor: a[1].into() }
+            }
+            "moveworkspacev2" => {
+                let a = Self::args(data, 3);
+                HyprlandEvent::MoveWorkspaceV2 { id: id(a[0]), name: a[1].into(), monitor: a[2].into() }
+            }
+            "renameworkspace" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::RenameWorkspace { id: id(a[0]), name: a[1].into() }
+            }
+            "activespecial" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::ActiveSpecial { workspace: a[0].into(), monitor: a[1].into() }
+            }
+            "activelayout" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::ActiveLayout { keyboard: a[0].into(), layout: a[1].into() }
+            }
+            "openwindow" => {
+                let a = Self::args(data, 4);
+                HyprlandEvent::OpenWindow {
+                    address: address(a[0]),
+                    workspace: a[1].into(),
+                    class: a[2].into(),
+                    title: a[3].into()
+                }
+            }
+            "closewindow" => HyprlandEvent::CloseWindow(address(data)),
+            "movewindow" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::MoveWindow { address: address(a[0]), workspace: a[1].into() }
+            }
+            "movewindowv2" => {
+                let a = Self::args(data, 3);
+                HyprlandEvent::MoveWindowV2 { address: address(a[0]), workspace_id: id(a[1]), workspace: a[2].into() }
+            }
+            "openlayer" => HyprlandEvent::OpenLayer(data.into()),
+            "closelayer" => HyprlandEvent::CloseLayer(data.into()),
+            "submap" => HyprlandEvent::Submap(data.into()),
+            "changefloatingmode" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::ChangeFloatingMode { address: address(a[0]), floating: a[1] == "1" }
+            }
+            "urgent" => HyprlandEvent::Urgent(address(data)),
+            "minimize" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::Minimize { address: address(a[0]), minimized: a[1] == "1" }
+            }
+            "screencast" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::Screencast { active: a[0] == "1", owner: a[1].parse().unwrap_or(0) }
+            }
+            "windowtitle" => HyprlandEvent::WindowTitle(address(data)),
+            "windowtitlev2" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::WindowTitleV2 { address: address(a[0]), title: a[1].into() }
+            }
+            "togglegroup" => {
+                let mut a = data.split(',');
+                HyprlandEvent::ToggleGroup {
+                    open: a.next() == Some("1"),
+                    addresses: a.filter(|x| !x.is_empty()).map(address).collect()
+                }
+            }
+            "moveintogroup" => HyprlandEvent::MoveIntoGroup(address(data)),
+            "moveoutofgroup" => HyprlandEvent::MoveOutOfGroup(address(data)),
+            "ignoregrouplock" => HyprlandEvent::IgnoreGroupLock(data == "1"),
+            "lockgroups" => HyprlandEvent::LockGroups(data == "1"),
+            "pin" => {
+                let a = Self::args(data, 2);
+                HyprlandEvent::Pin { address: address(a[0]), pinned: a[1] == "1" }
+            }
+            "configreloaded" => HyprlandEvent::ConfigReloaded,
+            _ => HyprlandEvent::Unknown { name: name.into(), data: data.into() }
+        }
+    }
+
+    pub fn compositor_event(&self) -> Option<CompositorEvent> {
+        match self {
+            HyprlandEvent::Workspace(_) |
+            HyprlandEvent::WorkspaceV2 { .. } |
+            HyprlandEvent::FocusedMon { .. } |
+            HyprlandEvent::CreateWorkspace(_) |
+            HyprlandEvent::CreateWorkspaceV2 { .. } |
+            HyprlandEvent::DestroyWorkspace(_) |
+            HyprlandEvent::DestroyWorkspaceV2 { .. } |
+            HyprlandEvent::MoveWorkspace { .. } |
+            HyprlandEvent::MoveWorkspaceV2 { .. } |
+            HyprlandEvent::RenameWorkspace { .. } |
+            HyprlandEvent::ActiveSpecial { .. } |
+            HyprlandEvent::Urgent(_) => Some(CompositorEvent::Workspaces),
+            HyprlandEvent::ActiveWindow { .. } |
+            HyprlandEvent::ActiveWindowV2(_) => Some(CompositorEvent::Focus),
+            HyprlandEvent::OpenWindow { .. } |
+            HyprlandEvent::CloseWindow(_) |
+            HyprlandEvent::MoveWindow { .. } |
+            HyprlandEvent::MoveWindowV2 { .. } |
+            HyprlandEvent::WindowTitle(_) |
+            HyprlandEvent::WindowTitleV2 { .. } |
+            HyprlandEvent::ChangeFloatingMode { .. } |
+            HyprlandEvent::Fullscreen(_) |
+            HyprlandEvent::Minimize { .. } |
+            HyprlandEvent::Pin { .. } => Some(CompositorEvent::Windows),
+            HyprlandEvent::MonitorAdded(_) |
+            HyprlandEvent::MonitorAddedV2 { .. } |
+            HyprlandEvent::MonitorRemoved(_) => Some(CompositorEvent::Outputs),
+            HyprlandEvent::ConfigReloaded => Some(CompositorEvent::Reloaded),
+            _ => None
+        }
+    }
+}
+
+// Replies of the `j/` requests on .socket.sock. Every field defaults, so that
+// older/newer Hyprland versions with missing or additional fields still parse
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WorkspaceRef {
+    pub id: i64,
+    pub name: String,
+}
+
+// older versions report fullscreen as bool, newer ones as a mode
+fn bool_or_int<'de, D: Deserializer<'de>>(d: D) -> Result<i64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrInt { Bool(bool), Int(i64) }
+    Ok(match BoolOrInt::deserialize(d)? {
+        BoolOrInt::Bool(b) => b as i64,
+        BoolOrInt::Int(i) => i,
+    })
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Client {
+    pub address: String,
+    pub mapped: bool,
+    pub hidden: bool,
+    pub at: (i64, i64),
+    pub size: (i64, i64),
+    pub workspace: WorkspaceRef,
+    pub floating: bool,
+    pub monitor: i64,
+    pub class: String,
+    pub title: String,
+    pub initial_class: String,
+    pub initial_title: String,
+    pub pid: i64,
+    pub xwayland: bool,
+    pub pinned: bool,
+    #[serde(deserialize_with = "bool_or_int")]
+    pub fullscreen: i64,
+    pub grouped: Vec<String>,
+    pub swallowing: String,
+    #[serde(rename = "focusHistoryID")]
+    pub focus_history_id: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Workspace {
+    pub id: i64,
+    pub name: String,
+    pub monitor: String,
+    #[serde(rename = "monitorID")]
+    pub monitor_id: i64,
+    pub windows: i64,
+    pub hasfullscreen: bool,
+    pub lastwindow: String,
+    pub lastwindowtitle: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Monitor {
+    pub id: i64,
+    pub name: String,
+    pub description: String,
+    pub make: String,
+    pub model: String,
+    pub serial: String,
+    pub width: i64,
+    pub height: i64,
+    pub refresh_rate: f64,
+    pub x: i64,
+    pub y: i64,
+    pub active_workspace: WorkspaceRef,
+    pub special_workspace: WorkspaceRef,
+    pub reserved: Vec<i64>,
+    pub scale: f64,
+    pub transform: i64,
+    pub focused: bool,
+    pub dpms_status: bool,
+    pub vrr: bool,
+    pub disabled: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Mouse {
+    pub address: String,
+    pub name: String,
+    pub default_speed: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Keyboard {
+    pub address: String,
+    pub name: String,
+    pub rules: String,
+    pub model: String,
+    pub layout: String,
+    pub variant: String,
+    pub options: String,
+    pub active_keymap: String,
+    pub main: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct InputDevice {
+    pub address: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Devices {
+    pub mice: Vec<Mouse>,
+    pub keyboards: Vec<Keyboard>,
+    pub tablets: Vec<InputDevice>,
+    pub touch: Vec<InputDevice>,
+    pub switches: Vec<InputDevice>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Layer {
+    pub address: String,
+    pub x: i64,
+    pub y: i64,
+    pub w: i64,
+    pub h: i64,
+    pub namespace: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct MonitorLayers {
+    pub levels: HashMap<String, Vec<Layer>>, // level ("0" background to "3" overlay) -> layers
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Bind {
+    pub locked: bool,
+    pub mouse: bool,
+    pub release: bool,
+    pub repeat: bool,
+    pub non_consuming: bool,
+    pub modmask: i64,
+    pub submap: String,
+    pub key: String,
+    pub keycode: i64,
+    pub catch_all: bool,
+    pub dispatcher: String,
+    pub arg: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WorkspaceTarget {
+    Id(i64),
+    Relative(i64), // relative to the current workspace id
+    MonitorRelative(i64), // relative among the workspaces on the current monitor
+    Name(String),
+    Special(Option<String>),
+    Previous,
+    Empty,
+}
+
+impl fmt::Display for WorkspaceTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WorkspaceTarget::Id(i) => write!(f, "{i}"),
+            WorkspaceTarget::Relative(i) => write!(f, "{i:+}"),
+            WorkspaceTarget::MonitorRelative(i) => write!(f, "m{i:+}"),
+            WorkspaceTarget::Name(n) => write!(f, "name:{n}"),
+            WorkspaceTarget::Special(None) => write!(f, "special"),
+            WorkspaceTarget::Special(Some(n)) => write!(f, "special:{n}"),
+            WorkspaceTarget::Previous => write!(f, "previous"),
+            WorkspaceTarget::Empty => write!(f, "empty"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WindowTarget {
+    Address(String),
+    Class(String), // regex
+    Title(String), // regex
+    Pid(i64),
+    Active,
+}
+
+impl fmt::Display for WindowTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowTarget::Address(a) => write!(f, "address:{a}"),
+            WindowTarget::Class(c) => write!(f, "class:{c}"),
+            WindowTarget::Title(t) => write!(f, "title:{t}"),
+            WindowTarget::Pid(p) => write!(f, "pid:{p}"),
+            WindowTarget::Active => write!(f, "activewindow"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Left, Right, Up, Down
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Left => "l",
+            Direction::Right => "r",
+            Direction::Up => "u",
+            Direction::Down => "d",
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum FullscreenMode {
+    Fullscreen = 0,
+    Maximize = 1,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum XkbLayout {
+    Next,
+    Prev,
+    Index(u32),
+}
+
+impl fmt::Display for XkbLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XkbLayout::Next => write!(f, "next"),
+            XkbLayout::Prev => write!(f, "prev"),
+            XkbLayout::Index(i) => write!(f, "{i}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Dispatch {
+    Workspace(WorkspaceTarget),
+    MoveToWorkspace(WorkspaceTarget, Option<WindowTarget>),
+    MoveToWorkspaceSilent(WorkspaceTarget, Option<WindowTarget>),
+    ToggleSpecialWorkspace(Option<String>),
+    FocusWindow(WindowTarget),
+    FocusMonitor(String),
+    MoveFocus(Direction),
+    CloseWindow(WindowTarget),
+    KillActive,
+    ToggleFloating(Option<WindowTarget>),
+    Pin(Option<WindowTarget>),
+    Fullscreen(FullscreenMode),
+    Exec(String),
+    SwitchXkbLayout(String, XkbLayout), // device name ("all", "current" or from `devices`)
+    Submap(String),
+    Custom(String, String), // any dispatcher not covered above
+}
+
+impl fmt::Display for Dispatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let with_window = |f: &mut fmt::Formatter<'_>, name: &str, ws: &WorkspaceTarget, w: &Option<WindowTarget>| match w {
+            Some(w) => write!(f, "{name} {ws},{w}"),
+            None => write!(f, "{name} {ws}"),
+        };
+        let optional = |f: &mut fmt::Formatter<'_>, name: &str, w: &Option<WindowTarget>| match w {
+            Some(w) => write!(f, "{name} {w}"),
+            None => write!(f, "{name}"),
+        };
+        match self {
+            Dispatch::Workspace(ws) => write!(f, "workspace {ws}"),
+            Dispatch::MoveToWorkspace(ws, w) => with_window(f, "movetoworkspace", ws, w),
+            Dispatch::MoveToWorkspaceSilent(ws, w) => with_window(f, "movetoworkspacesilent", ws, w),
+            Dispatch::ToggleSpecialWorkspace(None) => write!(f, "togglespecialworkspace"),
+            Dispatch::ToggleSpecialWorkspace(Some(n)) => write!(f, "togglespecialworkspace {n}"),
+            Dispatch::FocusWindow(w) => write!(f, "focuswindow {w}"),
+            Dispatch::FocusMonitor(m) => write!(f, "focusmonitor {m}"),
+            Dispatch::MoveFocus(d) => write!(f, "movefocus {d}"),
+            Dispatch::CloseWindow(w) => write!(f, "closewindow {w}"),
+            Dispatch::KillActive => write!(f, "killactive"),
+            Dispatch::ToggleFloating(w) => optional(f, "togglefloating", w),
+            Dispatch::Pin(w) => optional(f, "pin", w),
+            Dispatch::Fullscreen(mode) => write!(f, "fullscreen {}", *mode as u8),
+            Dispatch::Exec(cmd) => write!(f, "exec {cmd}"),
+            Dispatch::SwitchXkbLayout(device, layout) => write!(f, "switchxkblayout {device} {layout}"),
+            Dispatch::Submap(name) => write!(f, "submap {name}"),
+            Dispatch::Custom(name, arg) if arg.is_empty() => write!(f, "{name}"),
+            Dispatch::Custom(name, arg) => write!(f, "{name} {arg}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionValue {
+    Int(i64),
+    Float(f64),
+    String(String),
+    Vec2(f64, f64),
+    Custom(String), // e.g. gaps as "5 5 5 5"
+}
+
+impl fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionValue::Int(i) => write!(f, "{i}"),
+            OptionValue::Float(x) => write!(f, "{x}"),
+            OptionValue::String(s) | OptionValue::Custom(s) => write!(f, "{s}"),
+            OptionValue::Vec2(x, y) => write!(f, "{x} {y}"),
+        }
+    }
+}
+
+// reply of `j/getoption`, only one of the value fields is present
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawOption {
+    option: String,
+    int: Option<i64>,
+    float: Option<f64>,
+    str: Option<String>,
+    vec2: Option<(f64, f64)>,
+    custom: Option<String>,
+    set: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HyprlandOption {
+    pub name: String,
+    pub value: OptionValue,
+    pub set: bool, // false if the option still has its default value
+}
+
+impl TryFrom<RawOption> for HyprlandOption {
+    type Error = std::io::Error;
+    fn try_from(raw: RawOption) -> Result<Self, Self::Error> {
+        let value = if let Some(i) = raw.int {
+            OptionValue::Int(i)
+        } else if let Some(x) = raw.float {
+            OptionValue::Float(x)
+        } else if let Some((x, y)) = raw.vec2 {
+            OptionValue::Vec2(x, y)
+        } else if let Some(s) = raw.str {
+            OptionValue::String(s)
+        } else if let Some(c) = raw.custom {
+            OptionValue::Custom(c)
+        } else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("option {} has no value", raw.option)));
+        };
+        Ok(Self { name: raw.option, value, set: raw.set })
+    }
+}
+
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Default)]
+#[repr(u8)]
+pub enum HyprlandState {
+    #[default]
+    Absent = 0, // no Hyprland instance found
+    Connected = 1,
+    Reconnecting = 2, // instance found, but the sockets aren't (yet) reachable
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HyprlandData {
+    pub state: HyprlandState,
+    pub monitors: Vec<Monitor>,
+    pub workspaces: Vec<Workspace>,
+    pub clients: Vec<Client>,
+    pub active_workspaces: HashMap<String, i64>, // monitor name -> workspace id
+    pub focused_monitor: String,
+    pub focused_client: Option<Client>,
+    pub submap: String,
+    pub layout: String,
+    pub options: HashMap<String, OptionValue>, // watched options only
+    pub urgent_workspaces: Vec<i64>, // ids with an urgent window that wasn't looked at yet
+}
+
+#[derive(Clone, Debug)]
+pub struct HyprlandSender {
+    pub event: Sender<HyprlandEvent>,
+    pub compositor: Sender<CompositorEvent>,
+    pub changed: Sender<Arc<RwLock<HyprlandData>>>,
+    pub state: Sender<Arc<RwLock<HyprlandData>>>,
+    pub monitors: Sender<Arc<RwLock<HyprlandData>>>,
+    pub workspaces: Sender<Arc<RwLock<HyprlandData>>>,
+    pub clients: Sender<Arc<RwLock<HyprlandData>>>,
+    pub active_workspaces: Sender<Arc<RwLock<HyprlandData>>>,
+    pub focused_monitor: Sender<Arc<RwLock<HyprlandData>>>,
+    pub focused_client: Sender<Arc<RwLock<HyprlandData>>>,
+    pub submap: Sender<Arc<RwLock<HyprlandData>>>,
+    pub layout: Sender<Arc<RwLock<HyprlandData>>>,
+    pub options: Sender<Arc<RwLock<HyprlandData>>>,
+    pub urgent_workspaces: Sender<Arc<RwLock<HyprlandData>>>,
+}
+
+impl HyprlandSender {
+    fn new() -> Self {
+        Self {
+            event: channel(30).0,
+            compositor: channel(30).0,
+            changed: channel(30).0,
+            state: channel(30).0,
+            monitors: channel(30).0,
+            workspaces: channel(30).0,
+            clients: channel(30).0,
+            active_workspaces: channel(30).0,
+            focused_monitor: channel(30).0,
+            focused_client: channel(30).0,
+            submap: channel(30).0,
+            layout: channel(30).0,
+            options: channel(30).0,
+            urgent_workspaces: channel(30).0,
+        }
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 pub struct HyprlandService {
-    instance_signature: String,
+    instance_signature: Option<String>,
     xdg_runtime_dir: String,
+    pub data: Arc<RwLock<HyprlandData>>,
+    pub sender: HyprlandSender,
 }
 
 
 impl HyprlandService {
+    // Never fails if Hyprland isn't running, `HyprlandData::state` reports it
+    // as absent instead and the service keeps looking for an instance
     pub async fn new() -> std::io::Result<Arc<RwLock<Self>>> {
 
         let res = Arc::new(RwLock::new(HyprlandService {
-            instance_signature: env::var("HYPRLAND_INSTANCE_SIGNATURE")
-                .expect("Hyprland is not running (HYPRLAND_INSTANCE_SIGNATURE not set)"),
-            xdg_runtime_dir: env::var("XDG_RUNTIME_DIR").unwrap_or(String::from("/"))
+            instance_signature: env::var("HYPRLAND_INSTANCE_SIGNATURE").ok(),
+            xdg_runtime_dir: env::var("XDG_RUNTIME_DIR").unwrap_or(String::from("/")),
+            data: Arc::new(RwLock::new(HyprlandData::default())),
+            sender: HyprlandSender::new(),
         }));
 
         {
-            let res = res.clone();
-            let r = res.read().await;
+            let service = res.clone();
 
-            let stream = r.connection("socket2").await?;
+            // initial attempt, so the data is seeded once `new` returns
+            let stream = res.write().await.reconnect().await;
 
             tokio::spawn(async move {
+                let mut stream = stream;
+                let mut backoff = MIN_BACKOFF;
                 loop {
-                    let ready = stream.ready(Interest::READABLE).await;
-                    if ready.is_err() {
-                        return
-                    }
-                    let ready = ready.unwrap();
-                    if ready.is_readable() {
-                        let mut data = vec![0; 1024];
-                        // Try to read data, this may still fail with `WouldBlock`
-                        // if the readiness event is a false positive.
-                        match stream.try_read(&mut data) {
-                            Ok(n) if n > 0 => {
-                                println!("read {} bytes", n);
-                                let x = String::from_utf8_lossy(&data[..n]);
-                                println!("x: {:?}", x);
-                            }
-                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                continue;
-                            }
-                            Err(e) => {
-                                break;
-                                // return Err(e.into());
+                    match stream {
+                        Ok(s) => {
+                            let connected = Instant::now();
+                            Self::listen(&service, s).await;
+                            // only a connection that lasted resets the backoff, a socket that
+                            // accepts and closes right away would reconnect in a tight loop otherwise
+                            if connected.elapsed() > MAX_BACKOFF {
+                                backoff = MIN_BACKOFF;
                             }
-                            _ => continue
+                            warn!(target: "hyprland", "socket2 closed, reconnecting in {:?}", backoff);
+                            service.write().await.set_state(HyprlandState::Reconnecting).await;
+                        }
+                        Err(e) => {
+                            debug!(target: "hyprland", "couldn't connect: {:?}, retrying in {:?}", e, backoff);
                         }
-
                     }
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    stream = service.write().await.reconnect().await;
                 }
             });
         }
@@ -64,43 +708,522 @@ impl HyprlandService {
         Ok(res)
     }
 
+    // returns once socket2 is closed or fails
+    async fn listen(service: &Arc<RwLock<Self>>, stream: UnixStream) {
+        // socket2 is newline framed, events may be split across reads
+        let mut lines = BufReader::new(stream).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    let event = HyprlandEvent::parse(&line);
+                    let mut writer = service.write().await;
+                    if let Err(e) = writer.handle_event(&event).await {
+                        warn!(target: "hyprland", "couldn't update state for {:?}: {:?}", event, e);
+                    }
+                    if let Some(e) = event.compositor_event() {
+                        let _ = writer.sender.compositor.send(e);
+                    }
+                    if writer.sender.event.send(event).is_err() {
+                        debug!(target: "hyprland", "No receiver");
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!(target: "hyprland", "socket2 read failed: {:?}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn reconnect(&mut self) -> std::io::Result<UnixStream> {
+        // the signature changes whenever Hyprland is restarted
+        if self.instance_dir().is_none() {
+            self.instance_signature = self.newest_instance();
+        }
+        if self.instance_signature.is_none() {
+            self.set_state(HyprlandState::Absent).await;
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no Hyprland instance found"));
+        }
+
+        // subscribe before seeding, so no event between the two gets lost
+        let stream = match self.connection("socket2").await {
+            Ok(s) => s,
+            // a crashed instance leaves its socket behind, so the signature may be stale
+            Err(e) => match self.newest_instance() {
+                Some(signature) if self.instance_signature.as_ref() != Some(&signature) => {
+                    debug!(target: "hyprland", "switching to instance {}", signature);
+                    self.instance_signature = Some(signature);
+                    match self.connection("socket2").await {
+                        Ok(s) => s,
+                        Err(e) => {
+                            self.set_state(HyprlandState::Reconnecting).await;
+                            return Err(e);
+                        }
+                    }
+                }
+                _ => {
+                    self.set_state(HyprlandState::Reconnecting).await;
+                    return Err(e);
+                }
+            }
+        };
+        if let Err(e) = self.sync().await {
+            self.set_state(HyprlandState::Reconnecting).await;
+            return Err(e);
+        }
+        self.set_state(HyprlandState::Connected).await;
+        Ok(stream)
+    }
+
+    fn runtime_dirs(&self) -> [PathBuf; 2] {
+        [PathBuf::from(&self.xdg_runtime_dir).join("hypr"), PathBuf::from("/tmp/hypr")]
+    }
+
+    fn instance_dir(&self) -> Option<PathBuf> {
+        let signature = self.instance_signature.as_ref()?;
+        self.runtime_dirs()
+            .into_iter()
+            .map(|d| d.join(signature))
+            .find(|d| d.join(".socket2.sock").exists())
+    }
+
+    // most recently started instance that still has its event socket
+    fn newest_instance(&self) -> Option<String> {
+        self.runtime_dirs()
+            .iter()
+            .filter_map(|d| std::fs::read_dir(d).ok())
+            .flatten()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().join(".socket2.sock").exists())
+            .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.file_name().into_string().ok()?)))
+            .max()
+            .map(|(_, signature)| signature)
+    }
+
+    async fn set_state(&mut self, state: HyprlandState) {
+        if self.data.read().await.state == state {
+            return
+        }
+        self.update_state(state).await;
+        self.update().await;
+    }
+
+    pub async fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_monitors().await?;
+        self.sync_workspaces().await?;
+        self.sync_clients().await?;
+        let focused = self.active_window().await?;
+        self.update_focused_client(focused).await;
+        let layout = self.devices()
+            .await?
+            .keyboards
+            .into_iter()
+            .find(|k| k.main)
+            .map(|k| k.active_keymap)
+            .unwrap_or_default();
+        self.update_layout(layout).await;
+        self.sync_options().await;
+        self.update().await;
+        Ok(())
+    }
+
+    // also covers `configreloaded`, which triggers a full sync
+    async fn sync_options(&mut self) {
+        let mut options = self.data.read().await.options.clone();
+        for (name, value) in options.iter_mut() {
+            match self.get_option(name).await {
+                Ok(option) => *value = option.value,
+                // a broken watch shouldn't take down the whole sync, the previous value is kept
+                Err(e) => warn!(target: "hyprland", "couldn't read option {}: {:?}", name, e),
+            }
+        }
+        self.update_options(options).await;
+    }
+
+    // keeps `HyprlandData::options` up to date for the given option
+    pub async fn watch_option(&mut self, name: &str) -> std::io::Result<()> {
+        let value = self.get_option(name).await?.value;
+        let mut options = self.data.read().await.options.clone();
+        options.insert(name.to_string(), value);
+        self.update_options(options).await;
+        self.update().await;
+        Ok(())
+    }
+
+    pub async fn unwatch_option(&mut self, name: &str) {
+        let mut options = self.data.read().await.options.clone();
+        options.remove(name);
+        self.update_options(options).await;
+        self.update().await;
+    }
+
+    async fn sync_monitors(&mut self) -> std::io::Result<()> {
+        let monitors = self.monitors().await?;
+        let active_workspaces = monitors
+            .iter()
+            .map(|m| (m.name.clone(), m.active_workspace.id))
+            .collect();
+        if let Some(m) = monitors.iter().find(|m| m.focused) {
+            self.update_focused_monitor(m.name.clone()).await;
+        }
+        self.update_active_workspaces(active_workspaces).await;
+        self.update_monitors(monitors).await;
+        Ok(())
+    }
+
+    async fn sync_workspaces(&mut self) -> std::io::Result<()> {
+        let workspaces = self.workspaces().await?;
+        self.update_workspaces(workspaces).await;
+        Ok(())
+    }
+
+    async fn sync_clients(&mut self) -> std::io::Result<()> {
+        let clients = self.clients().await?;
+        self.set_clients(clients).await;
+        Ok(())
+    }
+
+    // keeps the focused client in line with the client list
+    async fn set_clients(&mut self, clients: Vec<Client>) {
+        let focused = self.data.read().await.focused_client.as_ref().and_then(|f|
+            clients.iter().find(|c| c.address == f.address).cloned()
+        );
+        self.update_clients(clients).await;
+        self.update_focused_client(focused).await;
+    }
+
+    async fn modify_client<F: FnOnce(&mut Client)>(&mut self, address: &str, f: F) {
+        let mut clients = self.data.read().await.clients.clone();
+        if let Some(c) = clients.iter_mut().find(|c| c.address == address) {
+            f(c);
+        }
+        self.set_clients(clients).await;
+    }
+
+    // workspace switches only report the new workspace, the monitors are kept in sync by hand
+    async fn set_active_workspace(&mut self, monitor: &str, workspace: WorkspaceRef) {
+        let (mut monitors, mut active_workspaces) = {
+            let r = self.data.read().await;
+            (r.monitors.clone(), r.active_workspaces.clone())
+        };
+        for m in monitors.iter_mut() {
+            m.focused = m.name == monitor;
+            if m.focused {
+                m.active_workspace = workspace.clone();
+            }
+        }
+        active_workspaces.insert(monitor.to_string(), workspace.id);
+        self.update_monitors(monitors).await;
+        self.update_active_workspaces(active_workspaces).await;
+        self.update_focused_monitor(monitor.to_string()).await;
+        self.clear_urgent(workspace.id).await;
+    }
+
+    async fn clear_urgent(&mut self, workspace: i64) {
+        let mut urgent = self.data.read().await.urgent_workspaces.clone();
+        urgent.retain(|x| *x != workspace);
+        self.update_urgent_workspaces(urgent).await;
+    }
+
+    async fn handle_event(&mut self, event: &HyprlandEvent) -> std::io::Result<()> {
+        match event {
+            HyprlandEvent::WorkspaceV2 { id, name } => {
+                let monitor = self.data.read().await.focused_monitor.clone();
+                self.set_active_workspace(&monitor, WorkspaceRef { id: *id, name: name.clone() }).await;
+            }
+            HyprlandEvent::FocusedMon { monitor, workspace } => {
+                let id = self.data.read().await.workspaces.iter().find(|w| &w.name == workspace).map(|w| w.id);
+                match id {
+                    Some(id) => self.set_active_workspace(monitor, WorkspaceRef { id, name: workspace.clone() }).await,
+                    // a workspace that was just created, the next sync brings it in
+                    None => self.sync_monitors().await?,
+                }
+            }
+            HyprlandEvent::Urgent(address) => {
+                let (workspace, mut urgent) = {
+                    let r = self.data.read().await;
+                    (r.clients.iter().find(|c| &c.address == address).map(|c| c.workspace.id), r.urgent_workspaces.clone())
+                };
+                if let Some(id) = workspace.filter(|id| !urgent.contains(id)) {
+                    urgent.push(id);
+                    self.update_urgent_workspaces(urgent).await;
+                }
+            }
+            HyprlandEvent::ActiveSpecial { .. } |
+            HyprlandEvent::MonitorRemoved(_) |
+            HyprlandEvent::MonitorAddedV2 { .. } |
+            HyprlandEvent::MoveWorkspaceV2 { .. } => {
+                self.sync_monitors().await?;
+                self.sync_workspaces().await?;
+            }
+            HyprlandEvent::CreateWorkspaceV2 { .. } |
+            HyprlandEvent::DestroyWorkspaceV2 { .. } |
+            HyprlandEvent::RenameWorkspace { .. } => {
+                self.sync_workspaces().await?;
+            }
+            HyprlandEvent::ActiveWindowV2(address) => {
+                let focused = match address {
+                    Some(a) => self.data.read().await.clients.iter().find(|c| &c.address == a).cloned(),
+                    None => None
+                };
+                if let Some(c) = &focused {
+                    self.clear_urgent(c.workspace.id).await;
+                }
+                self.update_focused_client(focused).await;
+            }
+            HyprlandEvent::OpenWindow { .. } |
+            HyprlandEvent::CloseWindow(_) |
+            HyprlandEvent::MoveWindowV2 { .. } |
+            HyprlandEvent::Fullscreen(_) => {
+                self.sync_clients().await?;
+                self.sync_workspaces().await?;
+            }
+            HyprlandEvent::WindowTitleV2 { address, title } => {
+                self.modify_client(address, |c| c.title = title.clone()).await;
+            }
+            HyprlandEvent::ChangeFloatingMode { address, floating } => {
+                self.modify_client(address, |c| c.floating = *floating).await;
+            }
+            HyprlandEvent::Pin { address, pinned } => {
+                self.modify_client(address, |c| c.pinned = *pinned).await;
+            }
+            HyprlandEvent::Submap(submap) => {
+                self.update_submap(submap.clone()).await;
+            }
+            HyprlandEvent::ActiveLayout { layout, .. } => {
+                self.update_layout(layout.clone()).await;
+            }
+            HyprlandEvent::ConfigReloaded => {
+                return self.sync().await;
+            }
+            _ => return Ok(())
+        }
+        self.update().await;
+        Ok(())
+    }
+
+    update!(update_state, state, HyprlandState);
+    update!(update_monitors, monitors, Vec<Monitor>);
+    update!(update_workspaces, workspaces, Vec<Workspace>);
+    update!(update_clients, clients, Vec<Client>);
+    update!(update_active_workspaces, active_workspaces, HashMap<String, i64>);
+    update!(update_focused_monitor, focused_monitor, String);
+    update!(update_focused_client, focused_client, Option<Client>);
+    update!(update_submap, submap, String);
+    update!(update_layout, layout, String);
+    update!(update_options, options, HashMap<String, OptionValue>);
+    update!(update_urgent_workspaces, urgent_workspaces, Vec<i64>);
+
+    async fn update(&mut self) {
+        match self.sender.changed.send(self.data.clone()) {
+            Ok(_) => {},
+            Err(_) => {debug!(target: "hyprland", "No receiver");}
+        }
+    }
+
     pub async fn connection(&self, socket: &str) -> std::io::Result<UnixStream> {
-        let sock_fp = |folder: &String| format!("{folder}/hypr/{}/.{socket}.sock", self.instance_signature);
-        let mut path_name = sock_fp(&self.xdg_runtime_dir);
-        let mut path = Path::new(&path_name);
-        if !path.exists() {
-            path_name = sock_fp(&"/tmp".into());
-            path = Path::new(&path_name);
-        }
-        let stream = UnixStream::connect(path).await?;
+        let dir = self.instance_dir().ok_or(
+            std::io::Error::new(std::io::ErrorKind::NotFound, "Hyprland is not running")
+        )?;
+        let stream = UnixStream::connect(dir.join(format!(".{socket}.sock"))).await?;
         Ok(stream)
+    }
+
+    // Hyprland answers a single request per connection and closes it afterwards
+    pub async fn message_async(&self, cmd: &str) -> std::io::Result<String> {
+        let mut stream = self.connection("socket").await?;
+
+        stream.write_all(cmd.as_bytes()).await?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await?;
+        Ok(String::from_utf8_lossy(&reply).into_owned())
+    }
+
+    // a batch shares one connection and is processed by Hyprland in one go,
+    // commands must therefore not contain `;`
+    pub async fn batch(&self, cmds: &[String]) -> std::io::Result<String> {
+        self.message_async(&format!("[[BATCH]]{}", cmds.join(";"))).await
+    }
+
+    // every command answers `ok` on success, anything else is an error message;
+    // a batch answers with the replies of its commands one after another
+    fn check_ok(reply: String, commands: usize) -> std::io::Result<()> {
+        let mut rest = reply.trim_start();
+        for i in 0..commands {
+            match rest.strip_prefix("ok") {
+                Some(x) => rest = x.trim_start(),
+                None if commands == 1 => return Err(std::io::Error::other(reply.trim().to_string())),
+                None => return Err(std::io::Error::other(format!("command {} of the batch failed: {}", i + 1, rest.trim()))),
+            }
+        }
+        if rest.is_empty() {
+            Ok(())
+        } else {
+            Err(std::io::Error::other(reply))
+        }
+    }
+
+    pub async fn dispatch(&self, dispatch: &Dispatch) -> std::io::Result<()> {
+        Self::check_ok(self.message_async(&format!("dispatch {dispatch}")).await?, 1)
+    }
+
+    // a ';' would end the command early and run the rest as another one
+    pub async fn dispatch_batch(&self, dispatches: &[Dispatch]) -> std::io::Result<()> {
+        let cmds = dispatches.iter().map(|d| format!("dispatch {d}")).collect::<Vec<_>>();
+        if let Some(cmd) = cmds.iter().find(|x| x.contains(';')) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("';' can't be used in a batch: {cmd}")))
+        }
+        Self::check_ok(self.batch(&cmds).await?, cmds.len())
+    }
+
+    pub async fn get_option(&self, name: &str) -> std::io::Result<HyprlandOption> {
+        let raw: RawOption = self.message_json(&format!("getoption {name}")).await?;
+        raw.try_into()
+    }
+
+    // setting a keyword doesn't emit `configreloaded`, so a watched option is refreshed here
+    pub async fn keyword(&mut self, name: &str, value: &OptionValue) -> std::io::Result<()> {
+        Self::check_ok(self.message_async(&format!("keyword {name} {value}")).await?, 1)?;
+        if self.data.read().await.options.contains_key(name) {
+            self.watch_option(name).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn message_json<T: DeserializeOwned>(&self, cmd: &str) -> std::io::Result<T> {
+        let reply = self.message_async(&format!("j/{cmd}")).await?;
+        Ok(serde_json::from_str(&reply)?)
+    }
+
+    pub async fn clients(&self) -> std::io::Result<Vec<Client>> {
+        self.message_json("clients").await
+    }
+
+    pub async fn workspaces(&self) -> std::io::Result<Vec<Workspace>> {
+        self.message_json("workspaces").await
+    }
+
+    pub async fn monitors(&self) -> std::io::Result<Vec<Monitor>> {
+        self.message_json("monitors").await
+    }
+
+    // Hyprland replies with `{}` if no window is focused
+    pub async fn active_window(&self) -> std::io::Result<Option<Client>> {
+        let client: Client = self.message_json("activewindow").await?;
+        Ok(if client.address.is_empty() { None } else { Some(client) })
+    }
+
+    pub async fn active_workspace(&self) -> std::io::Result<Workspace> {
+        self.message_json("activeworkspace").await
+    }
+
+    pub async fn devices(&self) -> std::io::Result<Devices> {
+        self.message_json("devices").await
+    }
+
+    // monitor name -> layers on that monitor
+    pub async fn layers(&self) -> std::io::Result<HashMap<String, MonitorLayers>> {
+        self.message_json("layers").await
+    }
+
+    pub async fn binds(&self) -> std::io::Result<Vec<Bind>> {
+        self.message_json("binds").await
+    }
+}
+
+impl From<&Client> for CompositorWindow {
+    fn from(value: &Client) -> Self {
+        Self {
+            id: value.address.clone(),
+            title: value.title.clone(),
+            app_id: value.class.clone(),
+            pid: value.pid,
+            workspace: value.workspace.name.clone(),
+            focused: false,
+            floating: value.floating,
+            fullscreen: value.fullscreen != 0,
+        }
+    }
+}
+
+impl Compositor for HyprlandService {
+    fn name(&self) -> &'static str {
+        "Hyprland"
+    }
+
+    fn workspaces(&self) -> BoxFuture<'_, std::io::Result<Vec<CompositorWorkspace>>> {
+        Box::pin(async move {
+            let data = self.data.read().await;
+            Ok(data.workspaces.iter().map(|w| {
+                let visible = data.active_workspaces.get(&w.monitor) == Some(&w.id);
+                CompositorWorkspace {
+                    id: w.id,
+                    name: w.name.clone(),
+                    output: w.monitor.clone(),
+                    focused: visible && w.monitor == data.focused_monitor,
+                    visible,
+                    urgent: data.urgent_workspaces.contains(&w.id),
+                }
+            }).collect())
+        })
+    }
+
+    fn windows(&self) -> BoxFuture<'_, std::io::Result<Vec<CompositorWindow>>> {
+        Box::pin(async move {
+            let data = self.data.read().await;
+            let focused = data.focused_client.as_ref().map(|c| c.address.clone());
+            Ok(data.clients.iter().map(|c| CompositorWindow {
+                focused: Some(&c.address) == focused.as_ref(),
+                ..c.into()
+            }).collect())
+        })
+    }
+
+    fn outputs(&self) -> BoxFuture<'_, std::io::Result<Vec<CompositorOutput>>> {
+        Box::pin(async move {
+            Ok(self.data.read().await.monitors.iter().map(|m| CompositorOutput {
+                name: m.name.clone(),
+                description: m.description.clone(),
+                x: m.x,
+                y: m.y,
+                width: m.width,
+                height: m.height,
+                scale: m.scale,
+                focused: m.focused,
+                active_workspace: m.active_workspace.name.clone(),
+            }).collect())
+        })
+    }
+
+    fn focused_window(&self) -> BoxFuture<'_, std::io::Result<Option<CompositorWindow>>> {
+        Box::pin(async move {
+            Ok(self.data.read().await.focused_client.as_ref().map(|c| CompositorWindow {
+                focused: true,
+                ..c.into()
+            }))
+        })
+    }
+
+    fn command(&self, cmd: CompositorCommand) -> BoxFuture<'_, std::io::Result<()>> {
+        let workspace = |name: String| match name.parse::<i64>() {
+            Ok(id) => WorkspaceTarget::Id(id),
+            Err(_) => WorkspaceTarget::Name(name),
+        };
+        let dispatch = match cmd {
+            CompositorCommand::FocusWorkspace(name) => Dispatch::Workspace(workspace(name)),
+            CompositorCommand::MoveToWorkspace(name) => Dispatch::MoveToWorkspace(workspace(name), None),
+            CompositorCommand::FocusWindow(address) => Dispatch::FocusWindow(WindowTarget::Address(address)),
+            CompositorCommand::CloseWindow(address) => Dispatch::CloseWindow(WindowTarget::Address(address)),
+            CompositorCommand::FocusOutput(name) => Dispatch::FocusMonitor(name),
+            CompositorCommand::ToggleFloating => Dispatch::ToggleFloating(None),
+            CompositorCommand::ToggleFullscreen => Dispatch::Fullscreen(FullscreenMode::Fullscreen),
+            CompositorCommand::Exec(cmd) => Dispatch::Exec(cmd),
+        };
+        Box::pin(async move { self.dispatch(&dispatch).await })
+    }
 
-                        // if ready.is_writable() {
-                        //     // Try to write data, this may still fail with `WouldBlock`
-                        //     // if the readiness event is a false positive.
-                        //     match stream.try_write(b"hello world") {
-                        //         Ok(n) => {
-                        //             println!("write {} bytes", n);
-                        //         }
-                        //         Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        //             continue;
-                        //         }
-                        //         Err(e) => {
-                        //             return Err(e.into());
-                        //         }
-                        //     }
-                        // }
-                    // }
-                // }
-                // Err(e) => { /* connection failed */ }
-            // }
-
-    }
-
-    pub async fn message_async(&self, _cmd: &String) -> std::io::Result<String> {
-        let stream = self.connection("socket").await?;
-
-        stream.writable().await?;
-        Ok(String::from("hi"))
+    fn subscribe(&self) -> Receiver<CompositorEvent> {
+        self.sender.compositor.subscribe()
     }
 }
@@ -2,7 +2,11 @@
 pub mod utils;
 pub mod applications;
 pub mod audio;
+pub mod pulseaudio;
+pub mod pipewire;
+pub mod compositor;
 pub mod hyprland;
+pub mod sway;
 pub mod battery;
 pub mod bluetooth;
 pub mod brightness;
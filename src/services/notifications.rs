@@ -1,8 +1,20 @@
-use image::{DynamicImage, ImageBuffer};
+use std::{collections::{BTreeMap, HashMap, HashSet}, path::{Path, PathBuf}, sync::{Arc, Weak}, time::Duration};
 
+use chrono::{Local, NaiveTime};
+use image::{DynamicImage, RgbaImage};
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::{sync::{broadcast::{channel, Sender}, watch, RwLock}, task::JoinHandle};
+use zbus::{interface, zvariant::OwnedValue, Connection, SignalContext};
 
+use super::applications::ApplicationsData;
+use super::utils::{PathGetter, async_file_watcher};
 
 
+const PATH: &str = "/org/freedesktop/Notifications";
+const NAME: &str = "org.freedesktop.Notifications";
+
 #[derive(Clone, Debug)]
 pub struct Action {
     pub id: String,
@@ -10,9 +22,11 @@ pub struct Action {
 }
 
 #[repr(u8)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Urgency {
     Critical = 2,
+    #[default]
     Normal = 1,
     Low = 0
 }
@@ -31,12 +45,12 @@ impl From<u8> for Urgency {
 
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Hints {
     pub actionIcons: bool,
-    pub category: String, 
+    pub category: String,
     pub desktopEntry: String,
-    pub imageData: DynamicImage,
+    pub imageData: Option<DynamicImage>,
     pub imagePath: String,
     pub resident: bool,
     pub soundFile: String,
@@ -47,7 +61,1289 @@ pub struct Hints {
     pub x: i64,
     pub y: i64,
 }
-//         const [w, h, rs, alpha, bps, _, data] = imageData // iiibiiay
-//             .recursiveUnpack<[number, number, number, boolean, number, number, GLib.Bytes]>();
 
+// raw image hint (iiibiiay): width, height, rowstride, has alpha, bits per sample, channels, data
+fn decode_image_data(value: &OwnedValue) -> Option<DynamicImage> {
+    let (width, height, rowstride, has_alpha, bits_per_sample, channels, data): (i32, i32, i32, bool, i32, i32, Vec<u8>) =
+        value.try_clone().ok()?.try_into().ok()?;
+    if width <= 0 || height <= 0 || rowstride <= 0 || channels < 3 || !(bits_per_sample == 8 || bits_per_sample == 16) {
+        return None
+    }
+    let (width, height, rowstride, channels) = (width as usize, height as usize, rowstride as usize, channels as usize);
+    let bytes_per_sample = bits_per_sample as usize / 8;
+    let row_len = width * channels * bytes_per_sample;
+    // the last row doesn't need to be padded to the full rowstride
+    if rowstride < row_len || data.len() < rowstride * (height - 1) + row_len {
+        return None
+    }
+
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for row in data.chunks(rowstride).take(height) {
+        for pixel in row[..row_len].chunks_exact(channels * bytes_per_sample) {
+            // 16 bit samples are native endian, only their high byte is kept
+            let sample = |i: usize| if bytes_per_sample == 1 {
+                pixel[i]
+            } else {
+                (u16::from_ne_bytes([pixel[2 * i], pixel[2 * i + 1]]) >> 8) as u8
+            };
+            rgba.extend_from_slice(&[sample(0), sample(1), sample(2), if has_alpha && channels > 3 { sample(3) } else { 255 }]);
+        }
+    }
+    RgbaImage::from_raw(width as u32, height as u32, rgba).map(DynamicImage::ImageRgba8)
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => { result.push(b); i += 3; }
+            (b, _) => { result.push(b); i += 1; }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+// file:// URI, absolute path or icon name from the icon theme
+fn resolve_image_path(s: &str) -> Option<PathBuf> {
+    if s.is_empty() {
+        return None
+    }
+    let path = if let Some(p) = s.strip_prefix("file://") {
+        PathBuf::from(percent_decode(p))
+    } else if s.starts_with('/') {
+        PathBuf::from(s)
+    } else {
+        linicon::lookup_icon(s).with_size(64).next()?.ok()?.path
+    };
+    if path.exists() { Some(path) } else { None }
+}
+
+#[derive(Clone, Debug)]
+pub enum NotificationImage {
+    Pixels(Arc<DynamicImage>), // shared by the clones handed to the listeners
+    File(PathBuf),
+}
+
+impl NotificationImage {
+    // precedence as in the spec: image-data, image-path, app_icon
+    // the decoded pixels are moved out of the hints, so they are only kept once
+    pub fn new(hints: &mut Hints, app_icon: &str) -> Option<Self> {
+        if let Some(image) = hints.imageData.take() {
+            return Some(NotificationImage::Pixels(Arc::new(image)))
+        }
+        resolve_image_path(&hints.imagePath)
+            .or_else(|| resolve_image_path(app_icon))
+            .map(NotificationImage::File)
+    }
+
+    // slint images aren't Send, so they are only created on the UI thread
+    pub fn to_slint(&self) -> Option<slint::Image> {
+        match self {
+            NotificationImage::Pixels(image) => {
+                let rgba = image.to_rgba8();
+                let buffer = slint::SharedPixelBuffer::<slint::Rgba8Pixel>::clone_from_slice(rgba.as_raw(), rgba.width(), rgba.height());
+                Some(slint::Image::from_rgba8(buffer))
+            }
+            NotificationImage::File(path) => slint::Image::load_from_path(path).ok(),
+        }
+    }
+}
+
+impl From<&HashMap<String, OwnedValue>> for Hints {
+    fn from(value: &HashMap<String, OwnedValue>) -> Self {
+        let string = |k: &str| value.get(k).and_then(|v| <&str>::try_from(v).ok()).map(String::from).unwrap_or_default();
+        let boolean = |k: &str| value.get(k).and_then(|v| bool::try_from(v).ok()).unwrap_or(false);
+        let int = |k: &str| value.get(k).and_then(|v| i32::try_from(v).ok()).unwrap_or(0) as i64;
+        Self {
+            actionIcons: boolean("action-icons"),
+            category: string("category"),
+            desktopEntry: string("desktop-entry"),
+            // image_data and icon_data are the names used by older spec versions
+            imageData: ["image-data", "image_data", "icon_data"]
+                .into_iter()
+                .find_map(|k| value.get(k).and_then(decode_image_data)),
+            imagePath: Some(string("image-path")).filter(|x| !x.is_empty()).unwrap_or(string("image_path")),
+            resident: boolean("resident"),
+            soundFile: string("sound-file"),
+            soundName: string("sound-name"),
+            supressSound: boolean("suppress-sound"),
+            transient: boolean("transient"),
+            urgency: value.get("urgency").and_then(|v| u8::try_from(v).ok()).unwrap_or(1).into(),
+            x: int("x"),
+            y: int("y"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TextSpan {
+    pub text: String,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub link: Option<String>,
+}
+
+// the body split into spans of the same style, as the markup allowed by the spec can't nest deeply
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StyledText {
+    pub spans: Vec<TextSpan>,
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = entity.strip_prefix('#')?;
+            let code = match code.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => code.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+// unknown or broken entities are kept as they are
+fn decode_entities(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('&') {
+        result.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+        match decoded {
+            Some((c, len)) => {
+                result.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+// value of a (possibly unquoted) attribute in the inside of a tag
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    // the '/' of a self-closing tag isn't part of the last value, e.g. <img alt=x/>
+    let tag = tag.trim_end();
+    let mut rest = tag.strip_suffix('/').unwrap_or(tag);
+    while let Some(i) = rest.to_ascii_lowercase().find(name) {
+        let preceded = rest[..i].ends_with(char::is_whitespace);
+        rest = rest[i + name.len()..].trim_start();
+        if !preceded {
+            continue
+        }
+        let Some(value) = rest.strip_prefix('=') else {
+            continue
+        };
+        let value = value.trim_start();
+        let value = match value.chars().next() {
+            Some(q @ ('"' | '\'')) => value[1..].split(q).next().unwrap_or_default(),
+            _ => value.split(char::is_whitespace).next().unwrap_or_default(),
+        };
+        return Some(decode_entities(value))
+    }
+    None
+}
+
+impl StyledText {
+    pub fn plain(text: &str) -> Self {
+        Self { spans: vec![TextSpan { text: text.to_string(), ..Default::default() }] }
+    }
+
+    // accepts broken markup: unmatched closing tags are ignored, unclosed ones last until the end
+    // and a '<' that doesn't start a tag is kept as text
+    pub fn parse(markup: &str) -> Self {
+        let mut result = Self::default();
+        let (mut bold, mut italic, mut underline) = (0u32, 0u32, 0u32);
+        let mut links: Vec<String> = Vec::new();
+        let mut text = String::new();
+        let mut rest = markup;
+
+        while !rest.is_empty() {
+            let Some(start) = rest.find('<') else {
+                text.push_str(rest);
+                break
+            };
+            text.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(end) = rest.find('>') else {
+                text.push_str(rest);
+                break
+            };
+            let tag = &rest[1..end];
+            let closing = tag.starts_with('/');
+            let name = tag
+                .trim_start_matches('/')
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .next()
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+                // e.g. "a < b > c"
+                text.push('<');
+                rest = &rest[1..];
+                continue
+            }
+            rest = &rest[end + 1..];
+
+            let style_changes = matches!(name.as_str(), "b" | "i" | "u" | "a");
+            if style_changes {
+                result.push(&mut text, bold > 0, italic > 0, underline > 0, links.last());
+            }
+            let counter = match name.as_str() {
+                "b" => Some(&mut bold),
+                "i" => Some(&mut italic),
+                "u" => Some(&mut underline),
+                _ => None
+            };
+            match (counter, name.as_str()) {
+                (Some(c), _) if closing => *c = c.saturating_sub(1),
+                (Some(c), _) => *c += 1,
+                (None, "a") if closing => { links.pop(); }
+                (None, "a") => links.push(attribute(tag, "href").unwrap_or_default()),
+                // images can't be shown inline, their description is used instead
+                (None, "img") => text.push_str(&attribute(tag, "alt").unwrap_or_default()),
+                (None, "br") => text.push('\n'),
+                _ => {} // unsupported tags are dropped, their content is kept
+            }
+        }
+        result.push(&mut text, bold > 0, italic > 0, underline > 0, links.last());
+        result
+    }
+
+    fn push(&mut self, text: &mut String, bold: bool, italic: bool, underline: bool, link: Option<&String>) {
+        if text.is_empty() {
+            return
+        }
+        let span = TextSpan {
+            text: decode_entities(&std::mem::take(text)),
+            bold,
+            italic,
+            underline,
+            link: link.filter(|x| !x.is_empty()).cloned(),
+        };
+        match self.spans.last_mut() {
+            Some(last) if (last.bold, last.italic, last.underline, &last.link) == (span.bold, span.italic, span.underline, &span.link) => {
+                last.text.push_str(&span.text);
+            }
+            _ => self.spans.push(span),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.spans.iter().map(|x| x.text.as_str()).collect()
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = &str> {
+        self.spans.iter().filter_map(|x| x.link.as_deref())
+    }
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    Expired = 1,
+    Dismissed = 2, // by the user
+    Closed = 3, // by a call to CloseNotification
+    Undefined = 4
+}
+
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub id: u32,
+    pub app_name: String,
+    pub app_icon: String,
+    pub summary: String,
+    pub body: String,
+    pub actions: Vec<Action>,
+    pub hints: Hints,
+    pub image: Option<NotificationImage>,
+    pub expire_timeout: i32, // ms, -1 = server default, 0 = never
+    pub time: i64, // unix timestamp of the last Notify
+    pub popup: bool, // false once the popup expired, the notification stays in the center
+    pub styled_body: StyledText,
+}
+
+// what is kept of a notification for the notification center, also after it was closed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u32,
+    pub app_name: String,
+    pub app_icon: String,
+    pub desktop_entry: String,
+    pub summary: String,
+    pub body: String,
+    pub urgency: Urgency,
+    pub time: i64,
+    pub image: Option<PathBuf>, // decoded image data is written to the cache
+    pub read: bool,
+    #[serde(default)]
+    pub styled_body: StyledText,
+}
+
+impl HistoryEntry {
+    // notifications are grouped by their desktop entry, if the sender set one
+    pub fn group(&self) -> &str {
+        if self.desktop_entry.is_empty() { &self.app_name } else { &self.desktop_entry }
+    }
+
+    // image is where decoded image data will be written, files are referenced as they are
+    fn new(notification: &Notification, image: Option<PathBuf>) -> Self {
+        let image = match &notification.image {
+            Some(NotificationImage::File(path)) => Some(path.clone()),
+            Some(NotificationImage::Pixels(_)) => image,
+            None => None,
+        };
+        Self {
+            id: notification.id,
+            app_name: notification.app_name.clone(),
+            app_icon: notification.app_icon.clone(),
+            desktop_entry: notification.hints.desktopEntry.clone(),
+            summary: notification.summary.clone(),
+            body: notification.body.clone(),
+            urgency: notification.hints.urgency,
+            time: notification.time,
+            image,
+            read: false,
+            styled_body: notification.styled_body.clone(),
+        }
+    }
+
+    // only images written by the history itself are removed
+    fn remove_image(&self, cache_dir: Option<&Path>) {
+        if let (Some(image), Some(dir)) = (&self.image, cache_dir) {
+            if image.starts_with(dir) {
+                let _ = std::fs::remove_file(image);
+            }
+        }
+    }
+}
+
+// desktop entry and icon theme lookups are slow, so the header of a group is resolved once per sender
+pub type GroupHeaders = HashMap<(String, String), (String, Option<PathBuf>)>;
+
+#[derive(Clone, Debug)]
+pub struct NotificationGroup {
+    pub key: String, // desktop entry or app name
+    pub name: String,
+    pub icon: Option<PathBuf>,
+    pub entries: Vec<HistoryEntry>, // newest first
+    pub unread: usize,
+    pub collapsed: bool,
+}
+
+impl NotificationGroup {
+    // the header shows the application as installed, not what the sender calls itself
+    fn new(key: &str, newest: &HistoryEntry, apps: &ApplicationsData, headers: &mut GroupHeaders, collapsed: bool) -> Self {
+        let (name, icon) = headers.entry((key.to_string(), newest.app_name.clone())).or_insert_with(|| {
+            let app = apps.by_desktop_entry(key).or_else(|| apps.by_name(&newest.app_name));
+            let name = match app {
+                Some(app) => app.name.clone(),
+                None if newest.app_name.is_empty() => key.to_string(),
+                None => newest.app_name.clone(),
+            };
+            let icon = app
+                .and_then(|x| x.icon_name.as_deref())
+                .and_then(resolve_image_path)
+                .or_else(|| resolve_image_path(&newest.app_icon));
+            (name, icon)
+        }).clone();
+        Self {
+            key: key.to_string(),
+            name,
+            icon,
+            entries: Vec::new(),
+            unread: 0,
+            collapsed,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // number of notifications hidden behind the newest one while collapsed
+    pub fn hidden(&self) -> usize {
+        if self.collapsed { self.len().saturating_sub(1) } else { 0 }
+    }
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|x| Regex::new(&x).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+// every given field has to match
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RuleMatch {
+    pub app_name: Option<String>,
+    pub desktop_entry: Option<String>,
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub summary: Option<Regex>,
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub body: Option<Regex>,
+    pub category: Option<String>,
+    pub urgency: Option<Urgency>,
+}
+
+impl RuleMatch {
+    pub fn matches(&self, n: &Notification) -> bool {
+        self.app_name.as_ref().is_none_or(|x| *x == n.app_name) &&
+        self.desktop_entry.as_ref().is_none_or(|x| *x == n.hints.desktopEntry) &&
+        self.summary.as_ref().is_none_or(|x| x.is_match(&n.summary)) &&
+        self.body.as_ref().is_none_or(|x| x.is_match(&n.body)) &&
+        self.category.as_ref().is_none_or(|x| *x == n.hints.category) &&
+        self.urgency.is_none_or(|x| x == n.hints.urgency)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleAction {
+    Drop,
+    Transient,
+    Urgency(Urgency),
+    Timeout(i32), // ms, same meaning as expire_timeout
+    Silence,
+    HistoryOnly, // recorded in the history without a popup or broadcast
+}
+
+// e.g. {"match": {"app_name": "Spotify"}, "actions": ["transient", {"timeout": 2000}]}
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    #[serde(rename = "match", default)]
+    pub match_: RuleMatch,
+    pub actions: Vec<RuleAction>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RuleOutcome {
+    Show,
+    HistoryOnly,
+    Drop,
+}
+
+impl Rule {
+    // rules are read from $XDG_CONFIG_HOME/ekslistence/notification_rules.json
+    pub fn path() -> std::io::Result<PathBuf> {
+        let mut path = PathGetter::config().map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        path.push("ekslistence");
+        std::fs::create_dir_all(&path)?;
+        path.push("notification_rules.json");
+        Ok(path)
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Vec<Rule>> {
+        if !path.exists() {
+            return Ok(Vec::new())
+        }
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    // matching rules are applied in order and see the changes of earlier ones, a drop ends the evaluation
+    pub fn apply_all(rules: &[Rule], notification: &mut Notification) -> RuleOutcome {
+        let mut outcome = RuleOutcome::Show;
+        for rule in rules {
+            if !rule.match_.matches(notification) {
+                continue
+            }
+            for action in &rule.actions {
+                match action {
+                    RuleAction::Drop => return RuleOutcome::Drop,
+                    RuleAction::Transient => notification.hints.transient = true,
+                    RuleAction::Urgency(u) => notification.hints.urgency = *u,
+                    RuleAction::Timeout(t) => notification.expire_timeout = *t,
+                    RuleAction::Silence => notification.hints.supressSound = true,
+                    RuleAction::HistoryOnly => outcome = RuleOutcome::HistoryOnly,
+                }
+            }
+        }
+        outcome
+    }
+}
+
+// daily do-not-disturb window, may span midnight (e.g. 22:00 to 07:00)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DndSchedule {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl DndSchedule {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct NotificationsData {
+    pub notifications: BTreeMap<u32, Notification>,
+    pub history: Vec<HistoryEntry>, // oldest first
+    pub collapsed: HashSet<String>, // keys of the collapsed groups
+    pub dnd: bool,
+}
+
+impl NotificationsData {
+    pub fn unread(&self) -> usize {
+        self.history.iter().filter(|x| !x.read).count()
+    }
+
+    // groups ordered by their newest notification
+    pub fn groups(&self, apps: &ApplicationsData, headers: &mut GroupHeaders) -> Vec<NotificationGroup> {
+        let mut groups: Vec<NotificationGroup> = Vec::new();
+        for entry in self.history.iter().rev() {
+            let index = match groups.iter().position(|x| x.key == entry.group()) {
+                Some(i) => i,
+                None => {
+                    let collapsed = self.collapsed.contains(entry.group());
+                    groups.push(NotificationGroup::new(entry.group(), entry, apps, headers, collapsed));
+                    groups.len() - 1
+                }
+            };
+            let group = &mut groups[index];
+            if !entry.read {
+                group.unread += 1;
+            }
+            group.entries.push(entry.clone());
+        }
+        groups
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NotificationSender {
+    pub changed: Sender<Arc<RwLock<NotificationsData>>>,
+    pub added: Sender<Notification>,
+    pub replaced: Sender<Notification>,
+    pub closed: Sender<(u32, CloseReason)>,
+    pub action_invoked: Sender<(u32, String)>,
+    pub dnd: Sender<Arc<RwLock<NotificationsData>>>,
+    pub history: Sender<Arc<RwLock<NotificationsData>>>,
+}
+
+impl NotificationSender {
+    fn new() -> Self {
+        Self {
+            changed: channel(30).0,
+            added: channel(30).0,
+            replaced: channel(30).0,
+            closed: channel(30).0,
+            action_invoked: channel(30).0,
+            dnd: channel(30).0,
+            history: channel(30).0,
+        }
+    }
+}
+
+pub struct NotificationService {
+    pub data: Arc<RwLock<NotificationsData>>,
+    pub sender: NotificationSender,
+    pub default_timeout: Duration, // for an expire_timeout of -1
+    pub dnd_schedule: Option<DndSchedule>,
+    pub dnd_allowlist: Vec<String>, // desktop entries that still get popups during DND
+    dnd_override: Option<bool>, // manual toggle, lasts until the schedule changes state
+    dnd_scheduled: bool,
+    pub history_limit: usize,
+    pub body_markup: bool, // parse the body instead of showing it as plain text
+    cache_dir: Option<PathBuf>, // None if the history can't be persisted
+    history_writer: Option<watch::Sender<Vec<HistoryEntry>>>, // latest history for the background writer
+    next_image: u64, // keeps image files of replaced notifications apart
+    pub rules: Vec<Rule>,
+    rules_watcher: Option<notify::RecommendedWatcher>, // keeps the live reload running
+    connection: Connection,
+    next_id: u32,
+    timers: HashMap<u32, JoinHandle<()>>,
+    this: Weak<RwLock<NotificationService>>, // for the expiry timers
+}
 
+// the object served on the bus, all the state lives in the service
+struct NotificationServer {
+    service: Arc<RwLock<NotificationService>>,
+}
+
+#[interface(name = "org.freedesktop.Notifications")]
+impl NotificationServer {
+    #[allow(clippy::too_many_arguments)]
+    async fn notify(
+        &self,
+        app_name: String,
+        replaces_id: u32,
+        app_icon: String,
+        summary: String,
+        body: String,
+        actions: Vec<String>,
+        hints: HashMap<String, OwnedValue>,
+        expire_timeout: i32,
+    ) -> u32 {
+        let mut hints: Hints = (&hints).into();
+        let notification = Notification {
+            id: replaces_id,
+            image: NotificationImage::new(&mut hints, &app_icon),
+            app_name,
+            app_icon,
+            summary,
+            body,
+            actions: actions
+                .chunks_exact(2)
+                .map(|x| Action { id: x[0].clone(), label: x[1].clone() })
+                .collect(),
+            hints,
+            expire_timeout,
+            time: chrono::Local::now().timestamp(),
+            popup: true,
+            styled_body: StyledText::default(), // filled in by the service, which knows if markup is enabled
+        };
+        self.service.write().await.notify(notification).await
+    }
+
+    async fn close_notification(&self, id: u32) {
+        self.service.write().await.close(id, CloseReason::Closed).await;
+    }
+
+    async fn get_capabilities(&self) -> Vec<String> {
+        self.service.read().await.capabilities()
+    }
+
+    async fn get_server_information(&self) -> (String, String, String, String) {
+        (
+            String::from("ekslistence"),
+            String::from("ekstdo"),
+            String::from(env!("CARGO_PKG_VERSION")),
+            String::from("1.2"),
+        )
+    }
+
+    #[zbus(signal)]
+    async fn notification_closed(ctxt: &SignalContext<'_>, id: u32, reason: u32) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn action_invoked(ctxt: &SignalContext<'_>, id: u32, action_key: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn activation_token(ctxt: &SignalContext<'_>, id: u32, activation_token: &str) -> zbus::Result<()>;
+}
+
+// not part of the spec, lets other tools (e.g. busctl) flip do-not-disturb
+struct DndServer {
+    service: Arc<RwLock<NotificationService>>,
+    data: Arc<RwLock<NotificationsData>>,
+}
+
+#[interface(name = "org.ekstdo.Ekslistence.Notifications")]
+impl DndServer {
+    #[zbus(property)]
+    async fn do_not_disturb(&self) -> bool {
+        self.data.read().await.dnd
+    }
+
+    // zbus emits PropertiesChanged after a setter, so the service doesn't
+    #[zbus(property)]
+    async fn set_do_not_disturb(&self, value: bool) {
+        let mut service = self.service.write().await;
+        service.dnd_override = Some(value);
+        service.apply_dnd().await;
+    }
+}
+
+impl NotificationService {
+    pub async fn new() -> zbus::Result<Arc<RwLock<Self>>> {
+        let connection = Connection::session().await?;
+        let cache_dir = match Self::cache_dir() {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                warn!(target: "notifications", "history won't be persisted: {:?}", e);
+                None
+            }
+        };
+        let history = cache_dir.as_deref().map(Self::load_history).unwrap_or_default();
+        let history_writer = cache_dir.clone().map(|dir| {
+            let (tx, rx) = watch::channel(history.clone());
+            tokio::spawn(Self::write_history(dir, rx));
+            tx
+        });
+        // ids of the history must stay unique after a restart
+        let next_id = history.iter().map(|x| x.id).max().map_or(1, |x| x.checked_add(1).unwrap_or(1));
+
+        let service = Arc::new_cyclic(|this| RwLock::new(Self {
+            data: Arc::new(RwLock::new(NotificationsData { history, ..Default::default() })),
+            sender: NotificationSender::new(),
+            default_timeout: Duration::from_secs(5),
+            dnd_schedule: None,
+            dnd_allowlist: Vec::new(),
+            dnd_override: None,
+            dnd_scheduled: false,
+            history_limit: 100,
+            body_markup: true,
+            cache_dir,
+            history_writer,
+            next_image: 0,
+            rules: Vec::new(),
+            rules_watcher: None,
+            connection: connection.clone(),
+            next_id,
+            timers: HashMap::new(),
+            this: this.clone(),
+        }));
+
+        let data = service.read().await.data.clone();
+        connection
+            .object_server()
+            .at(PATH, NotificationServer { service: service.clone() })
+            .await?;
+        connection
+            .object_server()
+            .at(PATH, DndServer { service: service.clone(), data })
+            .await?;
+        // fails if another daemon (e.g. mako or dunst) owns the name, the servers must not keep the service alive then
+        if let Err(e) = connection.request_name(NAME).await {
+            let _ = connection.object_server().remove::<NotificationServer, _>(PATH).await;
+            let _ = connection.object_server().remove::<DndServer, _>(PATH).await;
+            return Err(e)
+        }
+
+        {
+            let service = Arc::downgrade(&service);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(30));
+                loop {
+                    interval.tick().await;
+                    match service.upgrade() {
+                        Some(s) => s.write().await.refresh_dnd().await,
+                        None => return
+                    }
+                }
+            });
+        }
+
+        Self::watch_rules(&service).await;
+
+        Ok(service)
+    }
+
+    async fn watch_rules(service: &Arc<RwLock<Self>>) {
+        let path = match Rule::path() {
+            Ok(path) => path,
+            Err(e) => {
+                warn!(target: "notifications", "rules won't be loaded: {:?}", e);
+                return
+            }
+        };
+        service.write().await.reload_rules(&path);
+
+        // the directory is watched, as the file itself might not exist yet
+        let Some(dir) = path.parent() else {
+            return
+        };
+        let (watcher, mut rx) = match async_file_watcher(dir).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(target: "notifications", "rules won't be reloaded: {:?}", e);
+                return
+            }
+        };
+        service.write().await.rules_watcher = Some(watcher);
+
+        let service = Arc::downgrade(service);
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                if !event.paths.contains(&path) {
+                    continue
+                }
+                match service.upgrade() {
+                    Some(s) => s.write().await.reload_rules(&path),
+                    None => return
+                }
+            }
+        });
+    }
+
+    // a broken rule file keeps the previous rules
+    fn reload_rules(&mut self, path: &Path) {
+        match Rule::load(path) {
+            Ok(rules) => {
+                info!(target: "notifications", "loaded {} notification rules", rules.len());
+                self.rules = rules;
+            }
+            Err(e) => warn!(target: "notifications", "couldn't load {:?}: {:?}", path, e),
+        }
+    }
+
+    pub fn capabilities(&self) -> Vec<String> {
+        let mut capabilities = vec![
+            String::from("actions"),
+            String::from("body"),
+            String::from("icon-static"),
+            String::from("persistence"),
+        ];
+        // senders only use markup if it's advertised
+        if self.body_markup {
+            capabilities.push(String::from("body-markup"));
+            capabilities.push(String::from("body-hyperlinks"));
+        }
+        capabilities
+    }
+
+    // returns the id of the notification, which stays the same when replacing
+    pub async fn notify(&mut self, mut notification: Notification) -> u32 {
+        notification.styled_body = if self.body_markup {
+            StyledText::parse(&notification.body)
+        } else {
+            StyledText::plain(&notification.body)
+        };
+        let outcome = Rule::apply_all(&self.rules, &mut notification);
+        notification.popup = !self.suppressed(&notification).await;
+        // an unknown replaces_id gets a new id too, a client chosen one would collide with next_id later
+        let open = self.data.read().await.notifications.contains_key(&notification.id);
+        if notification.id == 0 || !open {
+            notification.id = self.next_id;
+            // 0 is reserved for "no notification"
+            self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        }
+        match outcome {
+            RuleOutcome::Show => {},
+            RuleOutcome::HistoryOnly => {
+                self.record(&notification).await;
+                return notification.id
+            }
+            RuleOutcome::Drop => return notification.id,
+        }
+        let replaced = self.data
+            .write()
+            .await
+            .notifications
+            .insert(notification.id, notification.clone())
+            .is_some();
+
+        let id = notification.id;
+        // transient notifications bypass the history
+        if !notification.hints.transient {
+            self.record(&notification).await;
+        }
+        self.schedule_expiry(&notification);
+        let result = if replaced {
+            self.sender.replaced.send(notification)
+        } else {
+            self.sender.added.send(notification)
+        };
+        if result.is_err() {
+            debug!(target: "notifications", "No receiver");
+        }
+        self.update().await;
+        id
+    }
+
+    // None if the notification never expires on its own
+    fn timeout(&self, notification: &Notification) -> Option<Duration> {
+        if notification.hints.urgency == Urgency::Critical {
+            return None
+        }
+        match notification.expire_timeout {
+            0 => None,
+            t if t < 0 => Some(self.default_timeout),
+            t => Some(Duration::from_millis(t as u64)),
+        }
+    }
+
+    fn schedule_expiry(&mut self, notification: &Notification) {
+        if let Some(timer) = self.timers.remove(&notification.id) {
+            timer.abort();
+        }
+        let Some(timeout) = self.timeout(notification) else {
+            return
+        };
+        let id = notification.id;
+        let this = self.this.clone();
+        self.timers.insert(id, tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if let Some(service) = this.upgrade() {
+                service.write().await.expire(id).await;
+            }
+        }));
+    }
+
+    // transient notifications are gone once they expire, all others only lose their popup
+    async fn expire(&mut self, id: u32) {
+        // called from the timer itself, so it must not be aborted
+        self.timers.remove(&id);
+        let transient = {
+            let mut w = self.data.write().await;
+            match w.notifications.get_mut(&id) {
+                Some(n) => {
+                    n.popup = false;
+                    n.hints.transient
+                }
+                None => return
+            }
+        };
+        if transient {
+            self.close(id, CloseReason::Expired).await;
+        } else {
+            self.update().await;
+        }
+    }
+
+    fn signal_context(&self) -> zbus::Result<SignalContext<'_>> {
+        SignalContext::new(&self.connection, PATH)
+    }
+
+    pub async fn close(&mut self, id: u32, reason: CloseReason) {
+        if let Some(timer) = self.timers.remove(&id) {
+            timer.abort();
+        }
+        if self.data.write().await.notifications.remove(&id).is_none() {
+            return
+        }
+        let signal = match self.signal_context() {
+            Ok(ctxt) => NotificationServer::notification_closed(&ctxt, id, reason as u32).await,
+            Err(e) => Err(e)
+        };
+        if let Err(e) = signal {
+            debug!(target: "notifications", "couldn't emit NotificationClosed: {:?}", e);
+        }
+        if self.sender.closed.send((id, reason)).is_err() {
+            debug!(target: "notifications", "No receiver");
+        }
+        self.update().await;
+    }
+
+    // closing from the bar, e.g. a click on the close button
+    pub async fn dismiss(&mut self, id: u32) {
+        self.close(id, CloseReason::Dismissed).await;
+    }
+
+    pub async fn invoke_action(&mut self, id: u32, action_key: &str) {
+        self.invoke_action_with_token(id, action_key, None).await;
+    }
+
+    // the xdg-activation token lets the sender focus its window, it has to be sent before the action
+    pub async fn invoke_action_with_token(&mut self, id: u32, action_key: &str, activation_token: Option<&str>) {
+        let resident = match self.data.read().await.notifications.get(&id) {
+            Some(n) if n.actions.iter().any(|a| a.id == action_key) => n.hints.resident,
+            _ => return
+        };
+        let signal = match self.signal_context() {
+            Ok(ctxt) => {
+                let token = match activation_token {
+                    Some(token) => NotificationServer::activation_token(&ctxt, id, token).await,
+                    None => Ok(())
+                };
+                match token {
+                    Ok(_) => NotificationServer::action_invoked(&ctxt, id, action_key).await,
+                    Err(e) => Err(e)
+                }
+            }
+            Err(e) => Err(e)
+        };
+        if let Err(e) = signal {
+            debug!(target: "notifications", "couldn't emit ActionInvoked: {:?}", e);
+        }
+        if self.sender.action_invoked.send((id, action_key.to_string())).is_err() {
+            debug!(target: "notifications", "No receiver");
+        }
+        // resident notifications stay around after an action was invoked
+        if !resident {
+            self.close(id, CloseReason::Dismissed).await;
+        }
+    }
+
+    fn cache_dir() -> std::io::Result<PathBuf> {
+        let mut path = PathGetter::cache().map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        path.push("notifications");
+        std::fs::create_dir_all(&path)?;
+        Ok(path)
+    }
+
+    fn load_history(cache_dir: &Path) -> Vec<HistoryEntry> {
+        let path = cache_dir.join("history.json");
+        if !path.exists() {
+            return Vec::new()
+        }
+        let history = std::fs::File::open(&path)
+            .map_err(serde_json::Error::io)
+            .and_then(|file| serde_json::from_reader::<_, Vec<HistoryEntry>>(std::io::BufReader::new(file)));
+        match history {
+            Ok(mut history) => {
+                // entries written before the body was parsed
+                for entry in history.iter_mut().filter(|x| x.styled_body.spans.is_empty()) {
+                    entry.styled_body = StyledText::plain(&entry.body);
+                }
+                history
+            }
+            Err(e) => {
+                warn!(target: "notifications", "couldn't read {:?}: {:?}", path, e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn save_history(dir: &Path, history: &[HistoryEntry]) -> std::io::Result<()> {
+        // written to a temporary file first, so a crash doesn't leave a truncated history
+        let tmp = dir.join("history.json.tmp");
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, history)?;
+        std::io::Write::flush(&mut writer)?;
+        std::fs::rename(tmp, dir.join("history.json"))
+    }
+
+    // one write at a time on a blocking thread, changes made in the meantime end up in the next one
+    async fn write_history(dir: PathBuf, mut rx: watch::Receiver<Vec<HistoryEntry>>) {
+        while rx.changed().await.is_ok() {
+            let history = rx.borrow_and_update().clone();
+            let dir = dir.clone();
+            match tokio::task::spawn_blocking(move || Self::save_history(&dir, &history)).await {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => warn!(target: "notifications", "couldn't save the history: {:?}", e),
+                Err(e) => warn!(target: "notifications", "couldn't save the history: {:?}", e),
+            }
+        }
+    }
+
+    // replacing a notification moves it to the end as unread
+    async fn record(&mut self, notification: &Notification) {
+        let image = match (&notification.image, &self.cache_dir) {
+            (Some(NotificationImage::Pixels(image)), Some(dir)) => {
+                let path = dir.join("images").join(format!("{}-{}.png", notification.id, self.next_image));
+                self.next_image += 1;
+                self.save_image(image.clone(), path.clone());
+                Some(path)
+            }
+            _ => None
+        };
+        let entry = HistoryEntry::new(notification, image);
+        self.modify_history(|history, cache_dir| {
+            history.retain(|x| {
+                if x.id == entry.id {
+                    x.remove_image(cache_dir);
+                    false
+                } else {
+                    true
+                }
+            });
+            history.push(entry);
+        }).await;
+    }
+
+    // the png is encoded on a blocking thread, the center picks it up with the next refresh
+    fn save_image(&self, image: Arc<DynamicImage>, path: PathBuf) {
+        let this = self.this.clone();
+        tokio::spawn(async move {
+            let result = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || {
+                    if let Some(dir) = path.parent() {
+                        std::fs::create_dir_all(dir)?;
+                    }
+                    image.save(&path)
+                }).await
+            };
+            match result {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => return warn!(target: "notifications", "couldn't save notification image: {:?}", e),
+                Err(e) => return warn!(target: "notifications", "couldn't save notification image: {:?}", e),
+            }
+            let Some(service) = this.upgrade() else {
+                return
+            };
+            let service = service.read().await;
+            // the entry may have been cleared or replaced while encoding
+            if service.data.read().await.history.iter().all(|x| x.image.as_ref() != Some(&path)) {
+                let _ = std::fs::remove_file(&path);
+                return
+            }
+            if service.sender.history.send(service.data.clone()).is_err() {
+                debug!(target: "notifications", "No receiver");
+            }
+        });
+    }
+
+    // applies f, enforces the size cap, persists the result and notifies the listeners
+    async fn modify_history<F: FnOnce(&mut Vec<HistoryEntry>, Option<&Path>)>(&mut self, f: F) {
+        let mut trimmed = Vec::new();
+        let history = {
+            let mut w = self.data.write().await;
+            f(&mut w.history, self.cache_dir.as_deref());
+            let overflow = w.history.len().saturating_sub(self.history_limit);
+            for entry in w.history.drain(..overflow) {
+                entry.remove_image(self.cache_dir.as_deref());
+                trimmed.push(entry.id);
+            }
+            w.history.clone()
+        };
+        if let Some(writer) = &self.history_writer {
+            writer.send_replace(history);
+        }
+        if self.sender.history.send(self.data.clone()).is_err() {
+            debug!(target: "notifications", "No receiver");
+        }
+        // trimmed notifications can't be dismissed from the center anymore, so they are closed here
+        for id in trimmed {
+            self.close(id, CloseReason::Expired).await;
+        }
+        self.update().await;
+    }
+
+    pub async fn mark_read(&mut self, id: u32) {
+        self.modify_history(|history, _| {
+            if let Some(entry) = history.iter_mut().find(|x| x.id == id) {
+                entry.read = true;
+            }
+        }).await;
+    }
+
+    pub async fn mark_all_read(&mut self) {
+        self.modify_history(|history, _| history.iter_mut().for_each(|x| x.read = true)).await;
+    }
+
+    // clearing from the center also dismisses the notification if it's still open
+    pub async fn clear(&mut self, id: u32) {
+        self.clear_where(|x| x.id == id).await;
+    }
+
+    pub async fn clear_all(&mut self) {
+        self.clear_where(|_| true).await;
+    }
+
+    pub async fn clear_app(&mut self, app_name: &str) {
+        self.clear_where(|x| x.app_name == app_name).await;
+    }
+
+    pub async fn dismiss_group(&mut self, key: &str) {
+        self.clear_where(|x| x.group() == key).await;
+        self.data.write().await.collapsed.remove(key);
+    }
+
+    pub async fn set_group_collapsed(&mut self, key: &str, collapsed: bool) {
+        {
+            let mut w = self.data.write().await;
+            let changed = if collapsed {
+                w.collapsed.insert(key.to_string())
+            } else {
+                w.collapsed.remove(key)
+            };
+            if !changed {
+                return
+            }
+        }
+        if self.sender.history.send(self.data.clone()).is_err() {
+            debug!(target: "notifications", "No receiver");
+        }
+        self.update().await;
+    }
+
+    pub async fn toggle_group(&mut self, key: &str) {
+        let collapsed = self.data.read().await.collapsed.contains(key);
+        self.set_group_collapsed(key, !collapsed).await;
+    }
+
+    async fn clear_where<F: Fn(&HistoryEntry) -> bool>(&mut self, f: F) {
+        let mut cleared = Vec::new();
+        self.modify_history(|history, cache_dir| {
+            history.retain(|x| {
+                if f(x) {
+                    x.remove_image(cache_dir);
+                    cleared.push(x.id);
+                    false
+                } else {
+                    true
+                }
+            });
+        }).await;
+        for id in cleared {
+            self.dismiss(id).await;
+        }
+    }
+
+    // during do-not-disturb notifications are only recorded, unless critical or allow-listed
+    async fn suppressed(&self, notification: &Notification) -> bool {
+        self.data.read().await.dnd &&
+            notification.hints.urgency != Urgency::Critical &&
+            !self.dnd_allowlist.contains(&notification.hints.desktopEntry)
+    }
+
+    pub async fn set_dnd(&mut self, dnd: bool) {
+        self.dnd_override = Some(dnd);
+        self.refresh_dnd().await;
+    }
+
+    pub async fn toggle_dnd(&mut self) {
+        let dnd = self.data.read().await.dnd;
+        self.set_dnd(!dnd).await;
+    }
+
+    pub async fn set_dnd_schedule(&mut self, schedule: Option<DndSchedule>) {
+        self.dnd_schedule = schedule;
+        self.dnd_override = None;
+        self.refresh_dnd().await;
+    }
+
+    async fn refresh_dnd(&mut self) {
+        if !self.apply_dnd().await {
+            return
+        }
+        // emitted from a separate task, a D-Bus setter holding the interface may be waiting for the service
+        let connection = self.connection.clone();
+        tokio::spawn(async move {
+            if let Ok(iface) = connection.object_server().interface::<_, DndServer>(PATH).await {
+                if let Err(e) = iface.get().await.do_not_disturb_changed(iface.signal_context()).await {
+                    debug!(target: "notifications", "couldn't emit PropertiesChanged: {:?}", e);
+                }
+            }
+        });
+    }
+
+    // returns whether do-not-disturb changed
+    async fn apply_dnd(&mut self) -> bool {
+        let now = Local::now().time();
+        let scheduled = self.dnd_schedule.is_some_and(|s| s.contains(now));
+        if scheduled != self.dnd_scheduled {
+            self.dnd_scheduled = scheduled;
+            self.dnd_override = None;
+        }
+        let dnd = self.dnd_override.unwrap_or(scheduled);
+        if self.data.read().await.dnd == dnd {
+            return false
+        }
+        self.update_dnd(dnd).await;
+        self.update().await;
+        true
+    }
+
+    update!(update_dnd, dnd, bool);
+
+    async fn update(&mut self) {
+        match self.sender.changed.send(self.data.clone()) {
+            Ok(_) => {},
+            Err(_) => {debug!(target: "notifications", "No receiver");}
+        }
+    }
+}
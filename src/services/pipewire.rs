@@ -0,0 +1,1016 @@
+use std::{cell::{Cell, RefCell}, collections::HashMap, io::Cursor, rc::{Rc, Weak}, thread};
+
+use ::pipewire as pw;
+use log::{debug, warn};
+use pulse::channelmap::{Map as ChannelMap, MapDef, Position};
+use pulse::context::subscribe::{Facility, Operation as SubscribeOperation};
+use pulse::def::{PortAvailable, SinkState, SourceState};
+use pulse::volume::{ChannelVolumes, Volume};
+use pw::{core::Core, device::{Device, DeviceListener}, metadata::{Metadata, MetadataListener}, node::{Node, NodeInfoRef, NodeListener, NodeState}, properties::properties, registry::{GlobalObject, Registry}, types::ObjectType};
+use pw::stream::{Stream, StreamFlags, StreamListener, StreamState};
+use pw::spa::{param::{audio::{AudioFormat, AudioInfoRaw}, ParamType}, pod::{deserialize::PodDeserializer, serialize::PodSerializer, Object, Pod, Property, Value, ValueArray}, utils::{dict::DictRef, Direction, Id, SpaTypes}};
+use pw::spa::sys as spa_sys;
+use tokio::sync::{mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, oneshot};
+
+use super::audio::{AppInfo, AudioBackend, AudioEvent, AudioServiceError, AudioTarget, Card, CardProfile, Port, StreamEntry, StreamType, APP_NAME};
+use super::utils::BoxFuture;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NodeKind {
+    Sink,
+    Source,
+    Playback, // application output, a sink input in PulseAudio terms
+    Capture, // application input, a source output in PulseAudio terms
+}
+
+impl NodeKind {
+    fn from_class(class: &str) -> Option<Self> {
+        match class {
+            "Stream/Output/Audio" => Some(Self::Playback),
+            "Stream/Input/Audio" => Some(Self::Capture),
+            x if x.starts_with("Audio/Sink") => Some(Self::Sink),
+            x if x.starts_with("Audio/Source") => Some(Self::Source),
+            _ => None,
+        }
+    }
+
+    fn facility(self) -> Facility {
+        match self {
+            Self::Sink => Facility::Sink,
+            Self::Source => Facility::Source,
+            Self::Playback => Facility::SinkInput,
+            Self::Capture => Facility::SourceOutput,
+        }
+    }
+}
+
+// PipeWire volumes are linear, PulseAudio's are cubic
+fn from_linear(x: f32) -> u32 {
+    ((x.max(0.) as f64).cbrt() * Volume::NORMAL.0 as f64).round() as u32
+}
+
+fn to_linear(x: Volume) -> f32 {
+    (x.0 as f64 / Volume::NORMAL.0 as f64).powi(3) as f32
+}
+
+fn position(channel: u32) -> Position {
+    match channel {
+        spa_sys::SPA_AUDIO_CHANNEL_MONO => Position::Mono,
+        spa_sys::SPA_AUDIO_CHANNEL_FL => Position::FrontLeft,
+        spa_sys::SPA_AUDIO_CHANNEL_FR => Position::FrontRight,
+        spa_sys::SPA_AUDIO_CHANNEL_FC => Position::FrontCenter,
+        spa_sys::SPA_AUDIO_CHANNEL_LFE => Position::Lfe,
+        spa_sys::SPA_AUDIO_CHANNEL_SL => Position::SideLeft,
+        spa_sys::SPA_AUDIO_CHANNEL_SR => Position::SideRight,
+        spa_sys::SPA_AUDIO_CHANNEL_FLC => Position::FrontLeftOfCenter,
+        spa_sys::SPA_AUDIO_CHANNEL_FRC => Position::FrontRightOfCenter,
+        spa_sys::SPA_AUDIO_CHANNEL_RC => Position::RearCenter,
+        spa_sys::SPA_AUDIO_CHANNEL_RL => Position::RearLeft,
+        spa_sys::SPA_AUDIO_CHANNEL_RR => Position::RearRight,
+        _ => Position::Aux0,
+    }
+}
+
+fn parse_pod(pod: &Pod) -> Option<Object> {
+    match PodDeserializer::deserialize_any_from(pod.as_bytes()) {
+        Ok((_, Value::Object(object))) => Some(object),
+        _ => None,
+    }
+}
+
+fn serialize_pod(object: Object) -> Option<Vec<u8>> {
+    PodSerializer::serialize(Cursor::new(Vec::new()), &Value::Object(object))
+        .ok()
+        .map(|(x, _)| x.into_inner())
+}
+
+fn to_map(dict: &DictRef) -> HashMap<String, String> {
+    dict.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+// {"name": "alsa_output..."} in the default metadata
+fn metadata_name(value: &str) -> Option<String> {
+    serde_json::from_str::<serde_json::Value>(value).ok()?
+        .get("name")?
+        .as_str()
+        .map(String::from)
+}
+
+// number of devices a profile creates of the given class, e.g. "Audio/Sink"
+fn class_count(classes: &Value, class: &str) -> u32 {
+    let Value::Struct(items) = classes else {
+        return 0
+    };
+    items.iter().filter_map(|x| match x {
+        Value::Struct(fields) => match fields.as_slice() {
+            [Value::String(c), Value::Int(n), ..] if c == class => Some(*n as u32),
+            _ => None,
+        },
+        _ => None,
+    }).sum()
+}
+
+// the index is what PipeWire knows the profile by
+fn profile(object: Object) -> (i32, CardProfile) {
+    let mut index = -1;
+    let mut profile = CardProfile {
+        name: String::new(),
+        description: String::new(),
+        priority: 0,
+        available: true,
+        sinks: 0,
+        sources: 0,
+    };
+    for property in object.properties {
+        match (property.key, property.value) {
+            (spa_sys::SPA_PARAM_PROFILE_index, Value::Int(x)) => index = x,
+            (spa_sys::SPA_PARAM_PROFILE_name, Value::String(x)) => profile.name = x,
+            (spa_sys::SPA_PARAM_PROFILE_description, Value::String(x)) => profile.description = x,
+            (spa_sys::SPA_PARAM_PROFILE_priority, Value::Int(x)) => profile.priority = x.max(0) as u32,
+            (spa_sys::SPA_PARAM_PROFILE_available, Value::Id(Id(x))) => profile.available = x != spa_sys::SPA_PARAM_AVAILABILITY_no,
+            (spa_sys::SPA_PARAM_PROFILE_classes, classes) => {
+                profile.sinks = class_count(&classes, "Audio/Sink");
+                profile.sources = class_count(&classes, "Audio/Source");
+            }
+            _ => {}
+        }
+    }
+    (index, profile)
+}
+
+// PipeWire's ports, a route belongs to some of the sinks and sources of a card
+struct Route {
+    index: i32,
+    device: i32, // the sink or source it's active on, only set for active routes
+    devices: Vec<i32>, // the sinks and sources it can be used with
+    port: Port,
+}
+
+impl Route {
+    fn new(object: Object) -> Self {
+        let mut route = Self {
+            index: -1,
+            device: -1,
+            devices: Vec::new(),
+            port: Port {
+                name: String::new(),
+                description: String::new(),
+                priority: 0,
+                available: PortAvailable::Unknown,
+                output: false,
+            },
+        };
+        for property in object.properties {
+            match (property.key, property.value) {
+                (spa_sys::SPA_PARAM_ROUTE_index, Value::Int(x)) => route.index = x,
+                (spa_sys::SPA_PARAM_ROUTE_device, Value::Int(x)) => route.device = x,
+                (spa_sys::SPA_PARAM_ROUTE_devices, Value::ValueArray(ValueArray::Int(x))) => route.devices = x,
+                (spa_sys::SPA_PARAM_ROUTE_name, Value::String(x)) => route.port.name = x,
+                (spa_sys::SPA_PARAM_ROUTE_description, Value::String(x)) => route.port.description = x,
+                (spa_sys::SPA_PARAM_ROUTE_priority, Value::Int(x)) => route.port.priority = x.max(0) as u32,
+                (spa_sys::SPA_PARAM_ROUTE_direction, Value::Id(Id(x))) => route.port.output = x == spa_sys::SPA_DIRECTION_OUTPUT,
+                (spa_sys::SPA_PARAM_ROUTE_available, Value::Id(Id(x))) => route.port.available = match x {
+                    spa_sys::SPA_PARAM_AVAILABILITY_no => PortAvailable::No,
+                    spa_sys::SPA_PARAM_AVAILABILITY_yes => PortAvailable::Yes,
+                    _ => PortAvailable::Unknown,
+                },
+                _ => {}
+            }
+        }
+        route
+    }
+}
+
+struct NodeEntry {
+    proxy: Node,
+    _listener: NodeListener,
+    kind: NodeKind,
+    props: HashMap<String, String>,
+    state: SinkState,
+    volume: Vec<u32>,
+    channel_map: Option<ChannelMap>,
+    is_muted: bool,
+    announced: bool, // the first info was seen
+}
+
+impl NodeEntry {
+    fn prop(&self, key: &str) -> Option<&str> {
+        self.props.get(key).map(String::as_str)
+    }
+
+    // peak meters and our own streams aren't shown in the mixer
+    fn is_helper(&self) -> bool {
+        self.prop("resample.peaks") == Some("true") || self.prop("application.name") == Some(APP_NAME)
+    }
+
+    fn app_info(&self, device: u32) -> AppInfo {
+        AppInfo {
+            name: self.prop("application.name").unwrap_or_default().to_string(), // empty if the client didn't set one
+            binary: self.prop("application.process.binary").unwrap_or_default().to_string(),
+            pid: self.prop("application.process.id").and_then(|x| x.parse().ok()),
+            media_title: self.prop("media.title")
+                .or_else(|| self.prop("media.name"))
+                .unwrap_or_default()
+                .to_string(),
+            media_role: self.prop("media.role").unwrap_or_default().to_string(),
+            corked: self.state != SinkState::Running,
+            device,
+        }
+    }
+
+    fn channel_map(&self) -> ChannelMap {
+        if let Some(map) = self.channel_map {
+            return map
+        }
+        let mut map = ChannelMap::default();
+        // nodes can be announced before their props arrive, pulse asserts on a map without channels
+        if self.volume.is_empty() {
+            map.init_mono();
+        } else {
+            map.init_auto(self.volume.len() as u8, MapDef::default());
+        }
+        map
+    }
+
+    // the card and the index of the node within its active profile
+    fn device<'a>(&self, state: &'a State) -> Option<(&'a DeviceEntry, i32)> {
+        let device = self.prop("device.id")
+            .and_then(|x| x.parse::<u32>().ok())
+            .and_then(|x| state.devices.get(&x))?;
+        let card_device = self.prop("card.profile.device").and_then(|x| x.parse().ok())?;
+        Some((device, card_device))
+    }
+
+    fn entry(&self, id: u32, state: &State) -> StreamEntry {
+        let name = self.prop("node.name").unwrap_or_default().to_string();
+        let (mut ports, mut active_port) = (Vec::new(), None);
+        let (description, icon_name, type_) = match self.kind {
+            NodeKind::Sink | NodeKind::Source => {
+                let device = self.prop("device.id")
+                    .and_then(|x| x.parse::<u32>().ok())
+                    .and_then(|x| state.devices.get(&x));
+                if let Some((device, card_device)) = self.device(state) {
+                    ports = device.routes.iter()
+                        .filter(|x| x.devices.contains(&card_device) && x.port.output == (self.kind == NodeKind::Sink))
+                        .map(|x| x.port.clone())
+                        .collect();
+                    active_port = device.active_routes.iter()
+                        .find(|x| x.device == card_device)
+                        .map(|x| x.port.name.clone());
+                }
+                let icon_name = self.prop("device.icon-name")
+                    .or_else(|| device.and_then(|x| x.prop("device.icon-name")))
+                    .unwrap_or("audio-card-analog-pci")
+                    .to_string();
+                let description = self.prop("node.description")
+                    .or_else(|| self.prop("node.nick"))
+                    .unwrap_or(name.as_str())
+                    .to_string();
+                let type_ = if self.kind == NodeKind::Sink {
+                    StreamType::Speaker(self.state, Volume::NORMAL.0)
+                } else {
+                    let state = match self.state {
+                        SinkState::Running => SourceState::Running,
+                        SinkState::Idle => SourceState::Idle,
+                        SinkState::Suspended => SourceState::Suspended,
+                        SinkState::Invalid => SourceState::Invalid,
+                    };
+                    StreamType::Microphones(state, Volume::NORMAL.0)
+                };
+                (description, icon_name, type_)
+            }
+            NodeKind::Playback | NodeKind::Capture => {
+                let fallback = if self.kind == NodeKind::Playback { "application-x-executable" } else { "record" };
+                let icon_name = self.prop("application.icon-name")
+                    .or_else(|| self.prop("media.icon-name"))
+                    .or_else(|| self.prop("application.process.binary"))
+                    .unwrap_or(fallback)
+                    .to_string();
+                let device = state.linked_device(id, self.kind);
+                let app = self.app_info(device);
+                let description = if app.media_title.is_empty() { name.clone() } else { app.media_title.clone() };
+                let type_ = if self.kind == NodeKind::Playback { StreamType::App(app) } else { StreamType::Recording(app) };
+                (description, icon_name, type_)
+            }
+        };
+        StreamEntry {
+            name,
+            description,
+            is_muted: self.is_muted,
+            volume: self.volume.clone(),
+            channel_map: self.channel_map(),
+            icon_name,
+            type_,
+            id,
+            ports,
+            active_port,
+        }
+    }
+
+    fn set_props(&self, properties: Vec<Property>) -> Result<(), AudioServiceError> {
+        let bytes = serialize_pod(Object {
+            type_: SpaTypes::ObjectParamProps.as_raw(),
+            id: ParamType::Props.as_raw(),
+            properties,
+        }).ok_or(AudioServiceError::OperationError)?;
+        let pod = Pod::from_bytes(&bytes).ok_or(AudioServiceError::OperationError)?;
+        self.proxy.set_param(ParamType::Props, 0, pod);
+        Ok(())
+    }
+}
+
+struct DeviceEntry {
+    proxy: Device,
+    _listener: DeviceListener,
+    props: HashMap<String, String>,
+    profiles: Vec<(i32, CardProfile)>, // with the index PipeWire knows them by
+    active_profile: Option<String>,
+    routes: Vec<Route>,
+    active_routes: Vec<Route>, // one per sink or source of the active profile
+}
+
+impl DeviceEntry {
+    fn prop(&self, key: &str) -> Option<&str> {
+        self.props.get(key).map(String::as_str)
+    }
+
+    fn card(&self, id: u32) -> Card {
+        let name = self.prop("device.name").unwrap_or_default().to_string();
+        Card {
+            id,
+            description: self.prop("device.description").unwrap_or(name.as_str()).to_string(),
+            name,
+            icon_name: self.prop("device.icon-name").unwrap_or("audio-card-analog-pci").to_string(),
+            profiles: self.profiles.iter().map(|(_, x)| x.clone()).collect(),
+            active_profile: self.active_profile.clone(),
+            ports: self.routes.iter().map(|x| x.port.clone()).collect(),
+        }
+    }
+}
+
+// a capture stream of a sink's monitor or a source, the listener owns the samples channel
+struct Meter {
+    stream: Stream,
+    _listener: StreamListener<Option<UnboundedSender<Vec<f32>>>>,
+}
+
+fn new_meter(core: &Core, name: String, capture_sink: bool, rate: u32, peaks: bool, samples: UnboundedSender<Vec<f32>>) -> Result<Meter, AudioServiceError> {
+    let error = |e: pw::Error| AudioServiceError::PipewireError(e.to_string());
+    let mut props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Capture",
+        *pw::keys::APP_NAME => APP_NAME,
+        // doesn't keep the device from suspending, like DONT_INHIBIT_AUTO_SUSPEND in PulseAudio
+        *pw::keys::NODE_PASSIVE => "true",
+        *pw::keys::STREAM_CAPTURE_SINK => if capture_sink { "true" } else { "false" },
+        *pw::keys::NODE_LATENCY => format!("{}/{}", (rate / 30).max(1), rate),
+        "target.object" => name,
+    };
+    // the resampler keeps the peaks instead of averaging, the same pipewire-pulse does for PEAK_DETECT
+    if peaks {
+        props.insert("resample.peaks", "true");
+    }
+    let stream = Stream::new(core, "Level meter", props).map_err(error)?;
+    let listener = stream.add_local_listener_with_user_data(Some(samples))
+        .process(|stream, samples| {
+            let Some(mut buffer) = stream.dequeue_buffer() else {
+                return
+            };
+            let Some(data) = buffer.datas_mut().first_mut() else {
+                return
+            };
+            let (offset, size) = (data.chunk().offset() as usize, data.chunk().size() as usize);
+            let Some(bytes) = data.data() else {
+                return
+            };
+            let chunk = bytes.get(offset..offset + size)
+                .unwrap_or_default()
+                .chunks_exact(4)
+                .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+                .collect();
+            if let Some(samples) = samples {
+                let _ = samples.send(chunk);
+            }
+        })
+        // closing the channel ends the meter on the service side
+        .state_changed(|_, samples, _, state| {
+            if matches!(state, StreamState::Error(_) | StreamState::Unconnected) {
+                samples.take();
+            }
+        })
+        .register()
+        .map_err(error)?;
+
+    let mut info = AudioInfoRaw::new();
+    info.set_format(AudioFormat::F32LE);
+    info.set_rate(rate);
+    info.set_channels(1);
+    let bytes = serialize_pod(Object {
+        type_: SpaTypes::ObjectParamFormat.as_raw(),
+        id: ParamType::EnumFormat.as_raw(),
+        properties: info.into(),
+    }).ok_or(AudioServiceError::OperationError)?;
+    let pod = Pod::from_bytes(&bytes).ok_or(AudioServiceError::OperationError)?;
+    // the meter belongs to its device, like DONT_MOVE in PulseAudio
+    stream.connect(Direction::Input, None, StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS | StreamFlags::DONT_RECONNECT, &mut [pod])
+        .map_err(error)?;
+    Ok(Meter { stream, _listener: listener })
+}
+
+// everything the backend knows, only touched on the pipewire thread
+#[derive(Default)]
+struct State {
+    nodes: HashMap<u32, NodeEntry>,
+    devices: HashMap<u32, DeviceEntry>,
+    links: HashMap<u32, (u32, u32)>, // output node, input node
+    metadata: Option<(u32, Metadata, MetadataListener)>,
+    default_sink: String,
+    default_source: String,
+    events: Option<UnboundedSender<AudioEvent>>,
+    core: Option<Core>,
+    meters: HashMap<u32, Meter>,
+    next_meter: u32,
+}
+
+impl State {
+    fn emit(&self, facility: Facility, operation: SubscribeOperation, index: u32) {
+        if let Some(events) = &self.events {
+            let _ = events.send(AudioEvent { facility, operation: Some(operation), index });
+        }
+    }
+
+    // the sink or source a stream is linked to
+    fn linked_device(&self, id: u32, kind: NodeKind) -> u32 {
+        self.links.values()
+            .find_map(|(output, input)| match kind {
+                NodeKind::Playback if *output == id => Some(*input),
+                NodeKind::Capture if *input == id => Some(*output),
+                _ => None,
+            })
+            .unwrap_or(u32::MAX)
+    }
+
+    // a new or removed link moves the stream on either end
+    fn emit_link(&self, output: u32, input: u32) {
+        for id in [output, input] {
+            if let Some(node) = self.nodes.get(&id) {
+                if matches!(node.kind, NodeKind::Playback | NodeKind::Capture) {
+                    self.emit(node.kind.facility(), SubscribeOperation::Changed, id);
+                }
+            }
+        }
+    }
+
+    fn node_by_name(&self, name: &str, kinds: &[NodeKind]) -> Option<u32> {
+        self.nodes.iter()
+            .find(|(_, x)| kinds.contains(&x.kind) && x.prop("node.name") == Some(name))
+            .map(|(id, _)| *id)
+    }
+
+    fn entries(&self, kind: NodeKind) -> Vec<StreamEntry> {
+        let mut entries = self.nodes.iter()
+            .filter(|(_, x)| x.kind == kind && x.announced && !x.is_helper())
+            .map(|(id, x)| x.entry(*id, self))
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|x| x.id);
+        entries
+    }
+
+    fn metadata(&self) -> Result<&Metadata, AudioServiceError> {
+        self.metadata.as_ref().map(|(_, x, _)| x).ok_or(AudioServiceError::OperationError)
+    }
+
+    fn node_info(&mut self, id: u32, info: &NodeInfoRef) {
+        let Some(node) = self.nodes.get_mut(&id) else {
+            return
+        };
+        if let Some(props) = info.props() {
+            node.props = to_map(props);
+        }
+        node.state = match info.state() {
+            NodeState::Running => SinkState::Running,
+            NodeState::Idle => SinkState::Idle,
+            NodeState::Suspended => SinkState::Suspended,
+            NodeState::Creating | NodeState::Error(_) => SinkState::Invalid,
+        };
+        let operation = if node.announced { SubscribeOperation::Changed } else { SubscribeOperation::New };
+        node.announced = true;
+        let kind = node.kind;
+        self.emit(kind.facility(), operation, id);
+    }
+
+    fn node_props(&mut self, id: u32, pod: &Pod) {
+        let (Some(node), Some(object)) = (self.nodes.get_mut(&id), parse_pod(pod)) else {
+            return
+        };
+        for property in object.properties {
+            match (property.key, property.value) {
+                (spa_sys::SPA_PROP_channelVolumes, Value::ValueArray(ValueArray::Float(volumes))) => {
+                    node.volume = volumes.into_iter().map(from_linear).collect();
+                }
+                (spa_sys::SPA_PROP_mute, Value::Bool(mute)) => node.is_muted = mute,
+                (spa_sys::SPA_PROP_channelMap, Value::ValueArray(ValueArray::Id(channels))) => {
+                    let mut map = ChannelMap::default();
+                    map.set_len(channels.len() as u8);
+                    for (p, Id(channel)) in map.get_mut().iter_mut().zip(channels) {
+                        *p = position(channel);
+                    }
+                    node.channel_map = Some(map);
+                }
+                _ => {}
+            }
+        }
+        let kind = node.kind;
+        if node.announced {
+            self.emit(kind.facility(), SubscribeOperation::Changed, id);
+        }
+    }
+
+    fn device_param(&mut self, id: u32, param: ParamType, index: u32, pod: &Pod) {
+        let (Some(device), Some(object)) = (self.devices.get_mut(&id), parse_pod(pod)) else {
+            return
+        };
+        // all params of a type are sent again whenever one of them changes
+        match param {
+            ParamType::EnumProfile => {
+                if index == 0 {
+                    device.profiles.clear();
+                }
+                device.profiles.push(profile(object));
+            }
+            ParamType::Profile => device.active_profile = Some(profile(object).1.name),
+            ParamType::EnumRoute => {
+                if index == 0 {
+                    device.routes.clear();
+                }
+                device.routes.push(Route::new(object));
+            }
+            ParamType::Route => {
+                if index == 0 {
+                    device.active_routes.clear();
+                }
+                device.active_routes.push(Route::new(object));
+            }
+            _ => return,
+        }
+        self.emit(Facility::Card, SubscribeOperation::Changed, id);
+    }
+
+    fn metadata_property(&mut self, subject: u32, key: Option<&str>, value: Option<&str>) {
+        if subject != 0 {
+            return
+        }
+        let name = value.and_then(metadata_name).unwrap_or_default();
+        match key {
+            Some("default.audio.sink") => self.default_sink = name,
+            Some("default.audio.source") => self.default_source = name,
+            // everything was cleared
+            None => {
+                self.default_sink.clear();
+                self.default_source.clear();
+            }
+            _ => return,
+        }
+        self.emit(Facility::Server, SubscribeOperation::Changed, 0);
+    }
+
+    fn global_remove(&mut self, id: u32) {
+        if let Some(node) = self.nodes.remove(&id) {
+            self.emit(node.kind.facility(), SubscribeOperation::Removed, id);
+        } else if self.devices.remove(&id).is_some() {
+            self.emit(Facility::Card, SubscribeOperation::Removed, id);
+        } else if let Some((output, input)) = self.links.remove(&id) {
+            self.emit_link(output, input);
+        } else if self.metadata.as_ref().is_some_and(|(x, _, _)| *x == id) {
+            self.metadata = None;
+        }
+    }
+}
+
+fn bind_node(state: &Weak<RefCell<State>>, registry: &Registry, global: &GlobalObject<&DictRef>, kind: NodeKind) -> Result<NodeEntry, pw::Error> {
+    let proxy: Node = registry.bind(global)?;
+    let id = global.id;
+    let listener = proxy.add_listener_local()
+        .info({
+            let state = state.clone();
+            move |info| if let Some(state) = state.upgrade() {
+                state.borrow_mut().node_info(id, info);
+            }
+        })
+        .param({
+            let state = state.clone();
+            move |_, param, _, _, pod| if let (Some(state), Some(pod), ParamType::Props) = (state.upgrade(), pod, param) {
+                state.borrow_mut().node_props(id, pod);
+            }
+        })
+        .register();
+    proxy.subscribe_params(&[ParamType::Props]);
+    Ok(NodeEntry {
+        proxy,
+        _listener: listener,
+        kind,
+        props: global.props.map(to_map).unwrap_or_default(),
+        state: SinkState::Invalid,
+        volume: Vec::new(),
+        channel_map: None,
+        is_muted: false,
+        announced: false,
+    })
+}
+
+fn bind_device(state: &Weak<RefCell<State>>, registry: &Registry, global: &GlobalObject<&DictRef>) -> Result<DeviceEntry, pw::Error> {
+    let proxy: Device = registry.bind(global)?;
+    let id = global.id;
+    let listener = proxy.add_listener_local()
+        .info({
+            let state = state.clone();
+            move |info| if let (Some(state), Some(props)) = (state.upgrade(), info.props()) {
+                let mut state = state.borrow_mut();
+                if let Some(device) = state.devices.get_mut(&id) {
+                    device.props = to_map(props);
+                    state.emit(Facility::Card, SubscribeOperation::Changed, id);
+                }
+            }
+        })
+        .param({
+            let state = state.clone();
+            move |_, param, index, _, pod| if let (Some(state), Some(pod)) = (state.upgrade(), pod) {
+                state.borrow_mut().device_param(id, param, index, pod);
+            }
+        })
+        .register();
+    proxy.subscribe_params(&[ParamType::EnumProfile, ParamType::Profile, ParamType::EnumRoute, ParamType::Route]);
+    Ok(DeviceEntry {
+        proxy,
+        _listener: listener,
+        props: global.props.map(to_map).unwrap_or_default(),
+        profiles: Vec::new(),
+        active_profile: None,
+        routes: Vec::new(),
+        active_routes: Vec::new(),
+    })
+}
+
+fn bind_metadata(state: &Weak<RefCell<State>>, registry: &Registry, global: &GlobalObject<&DictRef>) -> Result<(u32, Metadata, MetadataListener), pw::Error> {
+    let proxy: Metadata = registry.bind(global)?;
+    let listener = proxy.add_listener_local()
+        .property({
+            let state = state.clone();
+            move |subject, key, _, value| {
+                if let Some(state) = state.upgrade() {
+                    state.borrow_mut().metadata_property(subject, key, value);
+                }
+                0
+            }
+        })
+        .register();
+    Ok((global.id, proxy, listener))
+}
+
+fn global(state: &Weak<RefCell<State>>, registry: &Registry, global: &GlobalObject<&DictRef>) {
+    let Some(strong) = state.upgrade() else {
+        return
+    };
+    let prop = |key| global.props.and_then(|x| x.get(key));
+    let result = match global.type_ {
+        ObjectType::Node => match prop("media.class").and_then(NodeKind::from_class) {
+            Some(kind) => bind_node(state, registry, global, kind)
+                .map(|x| { strong.borrow_mut().nodes.insert(global.id, x); }),
+            None => Ok(()),
+        },
+        ObjectType::Device if prop("media.class") == Some("Audio/Device") => bind_device(state, registry, global)
+            .map(|x| {
+                let mut state = strong.borrow_mut();
+                state.devices.insert(global.id, x);
+                state.emit(Facility::Card, SubscribeOperation::New, global.id);
+            }),
+        ObjectType::Metadata if prop("metadata.name") == Some("default") => bind_metadata(state, registry, global)
+            .map(|x| { strong.borrow_mut().metadata = Some(x); }),
+        ObjectType::Link => {
+            let output = prop("link.output.node").and_then(|x| x.parse().ok());
+            let input = prop("link.input.node").and_then(|x| x.parse().ok());
+            if let (Some(output), Some(input)) = (output, input) {
+                let mut state = strong.borrow_mut();
+                state.links.insert(global.id, (output, input));
+                state.emit_link(output, input);
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    };
+    if let Err(e) = result {
+        warn!(target: "audio", "couldn't bind {:?} {}: {}", global.type_, global.id, e);
+    }
+}
+
+
+// runs on the pipewire thread
+type Job = Box<dyn FnOnce(&mut State) + Send>;
+
+enum Message {
+    Job(Job),
+    Quit,
+}
+
+// the PipeWire objects aren't Send either, so this works like the pulse backend:
+// a thread owns the main loop and the backend passes jobs to it
+pub struct PipewireBackend {
+    jobs: pw::channel::Sender<Message>,
+}
+
+impl PipewireBackend {
+    fn run(jobs: pw::channel::Receiver<Message>, ready: oneshot::Sender<Result<(), AudioServiceError>>) {
+        let error = |e: pw::Error| AudioServiceError::PipewireError(e.to_string());
+        pw::init();
+        let main_loop = match pw::main_loop::MainLoop::new(None) {
+            Ok(x) => x,
+            Err(_) => {
+                let _ = ready.send(Err(AudioServiceError::NewMainloopError));
+                return
+            }
+        };
+        let context = match pw::context::Context::new(&main_loop) {
+            Ok(x) => x,
+            Err(_) => {
+                let _ = ready.send(Err(AudioServiceError::NewContextError));
+                return
+            }
+        };
+        let (core, registry) = match context.connect(None).and_then(|core| Ok((core.get_registry()?, core))) {
+            Ok((registry, core)) => (core, Rc::new(registry)),
+            Err(e) => {
+                let _ = ready.send(Err(error(e)));
+                return
+            }
+        };
+
+        let state = Rc::new(RefCell::new(State { core: Some(core.clone()), ..Default::default() }));
+
+        let _registry_listener = registry.add_listener_local()
+            .global({
+                let state = Rc::downgrade(&state);
+                let registry = Rc::downgrade(&registry);
+                move |obj| if let Some(registry) = registry.upgrade() {
+                    global(&state, &registry, obj);
+                }
+            })
+            .global_remove({
+                let state = Rc::downgrade(&state);
+                move |id| if let Some(state) = state.upgrade() {
+                    state.borrow_mut().global_remove(id);
+                }
+            })
+            .register();
+
+        // the first roundtrip announces the globals, the second one the info and params of the bound objects
+        let ready = RefCell::new(Some(ready));
+        let rounds = Cell::new(0);
+        let pending = Rc::new(Cell::new(0));
+        let _core_listener = core.add_listener_local()
+            .done({
+                let core = core.clone();
+                let pending = pending.clone();
+                move |id, seq| {
+                    if id != pw::core::PW_ID_CORE || seq.seq() != pending.get() {
+                        return
+                    }
+                    rounds.set(rounds.get() + 1);
+                    if rounds.get() < 2 {
+                        match core.sync(0) {
+                            Ok(seq) => pending.set(seq.seq()),
+                            Err(e) => if let Some(ready) = ready.borrow_mut().take() {
+                                let _ = ready.send(Err(error(e)));
+                            },
+                        }
+                    } else if let Some(ready) = ready.borrow_mut().take() {
+                        let _ = ready.send(Ok(()));
+                    }
+                }
+            })
+            .error(|id, seq, res, message| {
+                warn!(target: "audio", "pipewire error on {} ({}): {} {}", id, seq, res, message);
+            })
+            .register();
+        match core.sync(0) {
+            Ok(seq) => pending.set(seq.seq()),
+            Err(e) => {
+                warn!(target: "audio", "couldn't sync with pipewire: {}", e);
+                return
+            }
+        }
+
+        let _jobs = jobs.attach(main_loop.loop_(), {
+            let state = state.clone();
+            let main_loop = main_loop.clone();
+            move |message| match message {
+                Message::Job(job) => job(&mut state.borrow_mut()),
+                Message::Quit => main_loop.quit(),
+            }
+        });
+
+        main_loop.run();
+        debug!(target: "audio", "pipewire thread stopped");
+    }
+
+    pub async fn new() -> Result<Self, AudioServiceError> {
+        let (jobs, rx) = pw::channel::channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        thread::Builder::new()
+            .name(String::from("pipewire"))
+            .spawn(move || Self::run(rx, ready_tx))
+            .map_err(|_| AudioServiceError::ThreadError)?;
+        ready_rx.await.map_err(|_| AudioServiceError::ThreadError)??;
+        Ok(Self { jobs })
+    }
+
+    // runs f on the pipewire thread
+    async fn call<T: Send + 'static, F>(&self, f: F) -> Result<T, AudioServiceError>
+        where F: FnOnce(&mut State) -> Result<T, AudioServiceError> + Send + 'static
+    {
+        let (tx, rx) = oneshot::channel();
+        self.jobs
+            .send(Message::Job(Box::new(move |state| { let _ = tx.send(f(state)); })))
+            .map_err(|_| AudioServiceError::ThreadError)?;
+        rx.await.map_err(|_| AudioServiceError::ThreadError)?
+    }
+}
+
+impl Drop for PipewireBackend {
+    fn drop(&mut self) {
+        let _ = self.jobs.send(Message::Quit);
+    }
+}
+
+impl AudioBackend for PipewireBackend {
+    fn name(&self) -> &'static str {
+        "PipeWire"
+    }
+
+    fn subscribe(&self) -> BoxFuture<'_, Result<UnboundedReceiver<AudioEvent>, AudioServiceError>> {
+        Box::pin(async move {
+            let (events_tx, events_rx) = unbounded_channel();
+            self.call(move |state| {
+                state.events = Some(events_tx);
+                Ok(())
+            }).await?;
+            Ok(events_rx)
+        })
+    }
+
+    fn speakers(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>> {
+        Box::pin(self.call(|state| Ok(state.entries(NodeKind::Sink))))
+    }
+
+    fn microphones(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>> {
+        Box::pin(self.call(|state| Ok(state.entries(NodeKind::Source))))
+    }
+
+    fn applications(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>> {
+        Box::pin(self.call(|state| Ok(state.entries(NodeKind::Playback))))
+    }
+
+    fn recorders(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>> {
+        Box::pin(self.call(|state| Ok(state.entries(NodeKind::Capture))))
+    }
+
+    fn cards(&self) -> BoxFuture<'_, Result<Vec<Card>, AudioServiceError>> {
+        Box::pin(self.call(|state| {
+            let mut cards = state.devices.iter().map(|(id, x)| x.card(*id)).collect::<Vec<_>>();
+            cards.sort_by_key(|x| x.id);
+            Ok(cards)
+        }))
+    }
+
+    fn defaults(&self) -> BoxFuture<'_, Result<(String, String), AudioServiceError>> {
+        Box::pin(self.call(|state| Ok((state.default_source.clone(), state.default_sink.clone()))))
+    }
+
+    // the configured default is the user's choice, the session manager derives the actual one from it
+    fn set_default(&self, target: AudioTarget) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |state| {
+            let (key, name) = match target {
+                AudioTarget::Speaker(name) => ("default.configured.audio.sink", name),
+                AudioTarget::Microphone(name) => ("default.configured.audio.source", name),
+                _ => return Err(AudioServiceError::OperationError),
+            };
+            let value = serde_json::json!({ "name": name }).to_string();
+            state.metadata()?.set_property(0, key, Some("Spa:String:JSON"), Some(&value));
+            Ok(())
+        }))
+    }
+
+    fn set_mute(&self, target: AudioTarget, yes: bool) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |state| {
+            let id = match target {
+                AudioTarget::Speaker(name) => state.node_by_name(&name, &[NodeKind::Sink]),
+                AudioTarget::Microphone(name) => state.node_by_name(&name, &[NodeKind::Source]),
+                AudioTarget::App(id) | AudioTarget::Recording(id) => Some(id),
+            };
+            let node = id.and_then(|x| state.nodes.get(&x)).ok_or(AudioServiceError::OperationError)?;
+            node.set_props(vec![Property::new(spa_sys::SPA_PROP_mute, Value::Bool(yes))])
+        }))
+    }
+
+    fn set_volume(&self, target: AudioTarget, volumes: ChannelVolumes) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |state| {
+            let id = match target {
+                AudioTarget::Speaker(name) => state.node_by_name(&name, &[NodeKind::Sink]),
+                AudioTarget::Microphone(name) => state.node_by_name(&name, &[NodeKind::Source]),
+                AudioTarget::App(id) | AudioTarget::Recording(id) => Some(id),
+            };
+            let node = id.and_then(|x| state.nodes.get(&x)).ok_or(AudioServiceError::OperationError)?;
+            let volumes = volumes.get().iter().map(|x| to_linear(*x)).collect();
+            node.set_props(vec![Property::new(spa_sys::SPA_PROP_channelVolumes, Value::ValueArray(ValueArray::Float(volumes)))])
+        }))
+    }
+
+    fn set_card_profile(&self, card_name: String, profile: String) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |state| {
+            let device = state.devices.values()
+                .find(|x| x.prop("device.name") == Some(card_name.as_str()))
+                .ok_or(AudioServiceError::OperationError)?;
+            let (index, _) = device.profiles.iter()
+                .find(|(_, x)| x.name == profile)
+                .ok_or(AudioServiceError::OperationError)?;
+            let bytes = serialize_pod(Object {
+                type_: SpaTypes::ObjectParamProfile.as_raw(),
+                id: ParamType::Profile.as_raw(),
+                properties: vec![
+                    Property::new(spa_sys::SPA_PARAM_PROFILE_index, Value::Int(*index)),
+                    Property::new(spa_sys::SPA_PARAM_PROFILE_save, Value::Bool(true)),
+                ],
+            }).ok_or(AudioServiceError::OperationError)?;
+            let pod = Pod::from_bytes(&bytes).ok_or(AudioServiceError::OperationError)?;
+            device.proxy.set_param(ParamType::Profile, 0, pod);
+            Ok(())
+        }))
+    }
+
+    fn set_port(&self, target: AudioTarget, port: String) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |state| {
+            let id = match target {
+                AudioTarget::Speaker(name) => state.node_by_name(&name, &[NodeKind::Sink]),
+                AudioTarget::Microphone(name) => state.node_by_name(&name, &[NodeKind::Source]),
+                _ => None,
+            };
+            let (device, card_device) = id.and_then(|x| state.nodes.get(&x))
+                .and_then(|x| x.device(state))
+                .ok_or(AudioServiceError::OperationError)?;
+            let route = device.routes.iter()
+                .find(|x| x.port.name == port && x.devices.contains(&card_device))
+                .ok_or(AudioServiceError::OperationError)?;
+            let bytes = serialize_pod(Object {
+                type_: SpaTypes::ObjectParamRoute.as_raw(),
+                id: ParamType::Route.as_raw(),
+                properties: vec![
+                    Property::new(spa_sys::SPA_PARAM_ROUTE_index, Value::Int(route.index)),
+                    Property::new(spa_sys::SPA_PARAM_ROUTE_device, Value::Int(card_device)),
+                    Property::new(spa_sys::SPA_PARAM_ROUTE_save, Value::Bool(true)),
+                ],
+            }).ok_or(AudioServiceError::OperationError)?;
+            let pod = Pod::from_bytes(&bytes).ok_or(AudioServiceError::OperationError)?;
+            device.proxy.set_param(ParamType::Route, 0, pod);
+            Ok(())
+        }))
+    }
+
+    // the session manager moves the stream once its target is set
+    fn move_stream(&self, target: AudioTarget, device: String) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |state| {
+            let (id, kind) = match target {
+                AudioTarget::App(id) => (id, NodeKind::Sink),
+                AudioTarget::Recording(id) => (id, NodeKind::Source),
+                _ => return Err(AudioServiceError::OperationError),
+            };
+            if !state.nodes.contains_key(&id) || state.node_by_name(&device, &[kind]).is_none() {
+                return Err(AudioServiceError::OperationError)
+            }
+            state.metadata()?.set_property(id, "target.object", None, Some(&device));
+            Ok(())
+        }))
+    }
+
+    fn open_meter(&self, target: AudioTarget, rate: u32, peaks: bool, samples: UnboundedSender<Vec<f32>>) -> BoxFuture<'_, Result<u32, AudioServiceError>> {
+        Box::pin(self.call(move |state| {
+            let (name, capture_sink) = match target {
+                AudioTarget::Speaker(name) => (name, true),
+                AudioTarget::Microphone(name) => (name, false),
+                _ => return Err(AudioServiceError::OperationError),
+            };
+            let core = state.core.as_ref().ok_or(AudioServiceError::OperationError)?;
+            let meter = new_meter(core, name, capture_sink, rate, peaks, samples)?;
+            let id = state.next_meter;
+            state.next_meter += 1;
+            state.meters.insert(id, meter);
+            Ok(id)
+        }))
+    }
+
+    fn close_meter(&self, id: u32) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |state| {
+            let meter = state.meters.remove(&id).ok_or(AudioServiceError::OperationError)?;
+            meter.stream.disconnect().map_err(|e| AudioServiceError::PipewireError(e.to_string()))
+        }))
+    }
+}
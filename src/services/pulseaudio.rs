@@ -0,0 +1,548 @@
+use std::{cell::{Cell, RefCell}, collections::HashMap, ops::Deref, rc::Rc, sync::mpsc, thread};
+
+use log::{debug, warn};
+use pulse::{callbacks::ListResult, context::{introspect::{CardInfo, ServerInfo, SinkInfo, SinkInputInfo, SourceInfo, SourceOutputInfo}, Context}, def::{BufferAttr, PortAvailable}, direction::FlagSet as DirectionFlagSet, mainloop::threaded::Mainloop, proplist::Proplist};
+use pulse::sample::{Format, Spec};
+use pulse::stream::{FlagSet as StreamFlagSet, PeekResult, State as StreamState, Stream};
+use pulse::context::{FlagSet as ContextFlagSet};
+use pulse::context::subscribe::InterestMaskSet;
+use pulse::volume::ChannelVolumes;
+use tokio::sync::{mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, oneshot};
+
+use super::audio::{AppInfo, AudioBackend, AudioEvent, AudioServiceError, AudioTarget, Card, CardProfile, Port, StreamEntry, StreamType, APP_NAME};
+use super::utils::BoxFuture;
+
+
+impl AppInfo {
+    fn new(proplist: &Proplist, corked: bool, device: u32) -> Self {
+        Self {
+            name: proplist.get_str("application.name").unwrap_or_default(), // empty if the client didn't set one
+            binary: proplist.get_str("application.process.binary").unwrap_or_default(),
+            pid: proplist.get_str("application.process.id").and_then(|x| x.parse().ok()),
+            media_title: proplist.get_str("media.title")
+                .or_else(|| proplist.get_str("media.name"))
+                .unwrap_or_default(),
+            media_role: proplist.get_str("media.role").unwrap_or_default(),
+            corked,
+            device,
+        }
+    }
+}
+
+// peak meters (e.g. from pavucontrol) and our own streams aren't shown in the mixer
+fn is_helper_stream(proplist: &Proplist, resample_method: Option<&str>) -> bool {
+    resample_method == Some("peaks") || proplist.get_str("application.name").as_deref() == Some(APP_NAME)
+}
+
+// the icon of the application, not of the device it plays on
+fn app_icon_name(proplist: &Proplist, fallback: &str) -> String {
+    proplist.get_str("application.icon_name")
+        .or_else(|| proplist.get_str("media.icon_name"))
+        .or_else(|| proplist.get_str("application.process.binary"))
+        .unwrap_or(fallback.into())
+}
+
+impl Port {
+    fn new(name: Option<&str>, description: Option<&str>, priority: u32, available: PortAvailable, output: bool) -> Self {
+        Self {
+            name: name.map(String::from).unwrap_or_default(),
+            description: description.map(String::from).unwrap_or_default(),
+            priority,
+            available,
+            output,
+        }
+    }
+}
+
+impl<'a> From<&CardInfo<'a>> for Card {
+    fn from(value: &CardInfo) -> Self {
+        let profiles = value.profiles.iter().map(|x| CardProfile {
+            name: x.name.as_deref().map(String::from).unwrap_or_default(),
+            description: x.description.as_deref().map(String::from).unwrap_or_default(),
+            priority: x.priority,
+            available: x.available,
+            sinks: x.n_sinks,
+            sources: x.n_sources,
+        }).collect();
+        let ports = value.ports.iter().map(|x| Port::new(
+            x.name.as_deref(),
+            x.description.as_deref(),
+            x.priority,
+            x.available,
+            x.direction.contains(DirectionFlagSet::OUTPUT),
+        )).collect();
+        let name = value.name.as_deref().map(String::from).unwrap_or_default();
+        Self {
+            id: value.index,
+            description: value.proplist.get_str("device.description").unwrap_or(name.clone()),
+            name,
+            icon_name: value.proplist.get_str("device.icon_name").unwrap_or("audio-card-analog-pci".into()),
+            profiles,
+            active_profile: value.active_profile.as_ref().and_then(|x| x.name.as_deref()).map(String::from),
+            ports,
+        }
+    }
+}
+
+impl<'a> From<&SinkInfo<'a>> for StreamEntry {
+    fn from(value: &SinkInfo) -> Self {
+        let base_volume = value.base_volume.0;
+        let channel_volumes = value.volume.get().iter().map(|x| x.0).collect::<Vec<_>>();
+        let icon_name = value.proplist.get_str("device.icon_name").unwrap_or("audio-card-analog-pci".into());
+        Self {
+            name: String::from(value.name.as_ref().unwrap().deref()),
+            description: String::from(value.description.as_ref().unwrap().deref()),
+            is_muted: value.mute,
+            type_: StreamType::Speaker(value.state, base_volume),
+            volume: channel_volumes,
+            channel_map: value.channel_map,
+            icon_name,
+            id: value.index,
+            ports: value.ports.iter().map(|x| Port::new(x.name.as_deref(), x.description.as_deref(), x.priority, x.available, true)).collect(),
+            active_port: value.active_port.as_ref().and_then(|x| x.name.as_deref()).map(String::from),
+        }
+    }
+}
+
+impl<'a> From<&SinkInputInfo<'a>> for StreamEntry {
+    fn from(value: &SinkInputInfo) -> Self {
+        let channel_volumes = value.volume.get().iter().map(|x| x.0).collect::<Vec<_>>();
+        let icon_name = app_icon_name(&value.proplist, "application-x-executable");
+        let app = AppInfo::new(&value.proplist, value.corked, value.sink);
+        let name = value.name.as_deref().map(String::from).unwrap_or_default();
+        Self {
+            description: if app.media_title.is_empty() { name.clone() } else { app.media_title.clone() },
+            name,
+            is_muted: value.mute,
+            type_: StreamType::App(app),
+            volume: channel_volumes,
+            channel_map: value.channel_map,
+            icon_name,
+            id: value.index,
+            ports: Vec::new(),
+            active_port: None,
+        }
+    }
+}
+
+impl<'a> From<&SourceInfo<'a>> for StreamEntry {
+    fn from(value: &SourceInfo) -> Self {
+        let base_volume = value.base_volume.0;
+        let channel_volumes = value.volume.get().iter().map(|x| x.0).collect::<Vec<_>>();
+        let icon_name = value.proplist.get_str("device.icon_name").unwrap_or("audio-card-analog-pci".into());
+        Self {
+            name: String::from(value.name.as_ref().unwrap().deref()),
+            description: String::from(value.description.as_ref().unwrap().deref()),
+            is_muted: value.mute,
+            type_: StreamType::Microphones(value.state, base_volume),
+            volume: channel_volumes,
+            channel_map: value.channel_map,
+            icon_name,
+            id: value.index,
+            ports: value.ports.iter().map(|x| Port::new(x.name.as_deref(), x.description.as_deref(), x.priority, x.available, false)).collect(),
+            active_port: value.active_port.as_ref().and_then(|x| x.name.as_deref()).map(String::from),
+        }
+    }
+}
+
+impl<'a> From<&SourceOutputInfo<'a>> for StreamEntry {
+    fn from(value: &SourceOutputInfo) -> Self {
+        let channel_volumes = value.volume.get().iter().map(|x| x.0).collect::<Vec<_>>();
+        let icon_name = app_icon_name(&value.proplist, "record");
+        let app = AppInfo::new(&value.proplist, value.corked, value.source);
+        let name = value.name.as_deref().map(String::from).unwrap_or_default();
+        let description = if app.media_title.is_empty() { name.clone() } else { app.media_title.clone() };
+        Self {
+            name,
+            description,
+            is_muted: value.mute,
+            type_: StreamType::Recording(app),
+            volume: channel_volumes,
+            channel_map: value.channel_map,
+            icon_name,
+            id: value.index,
+            ports: Vec::new(),
+            active_port: None,
+        }
+    }
+}
+
+
+// runs on the pulse thread with the mainloop locked
+type Job = Box<dyn FnOnce(&mut Context) + Send>;
+
+thread_local! {
+    // level meter streams by id, only touched by jobs
+    static METERS: RefCell<HashMap<u32, Rc<RefCell<Stream>>>> = RefCell::new(HashMap::new());
+    static NEXT_METER: Cell<u32> = const { Cell::new(0) };
+}
+
+// the callbacks run on the mainloop thread while the job that connected the stream may still hold it
+fn new_meter(context: &mut Context, device: &str, rate: u32, peaks: bool, samples: UnboundedSender<Vec<f32>>) -> Result<Rc<RefCell<Stream>>, AudioServiceError> {
+    let spec = Spec { format: Format::FLOAT32NE, rate, channels: 1 };
+    let stream = Stream::new(context, "Level meter", &spec, None).ok_or(AudioServiceError::OperationError)?;
+    let stream = Rc::new(RefCell::new(stream));
+    let samples = Rc::new(RefCell::new(Some(samples)));
+
+    let mut s = stream.borrow_mut();
+    s.set_read_callback(Some(Box::new({
+        let stream = Rc::downgrade(&stream);
+        let samples = samples.clone();
+        move |_| {
+            let Some(stream) = stream.upgrade() else {
+                return
+            };
+            let Ok(mut stream) = stream.try_borrow_mut() else {
+                return
+            };
+            match stream.peek() {
+                Ok(PeekResult::Data(data)) => {
+                    let chunk = data.chunks_exact(4)
+                        .map(|x| f32::from_ne_bytes([x[0], x[1], x[2], x[3]]))
+                        .collect();
+                    if let Some(samples) = samples.borrow().as_ref() {
+                        let _ = samples.send(chunk);
+                    }
+                    let _ = stream.discard();
+                }
+                Ok(PeekResult::Hole(_)) => { let _ = stream.discard(); }
+                _ => {}
+            }
+        }
+    })));
+    // closing the channel ends the meter on the service side
+    s.set_state_callback(Some(Box::new({
+        let stream = Rc::downgrade(&stream);
+        move || {
+            let Some(stream) = stream.upgrade() else {
+                return
+            };
+            let Ok(stream) = stream.try_borrow() else {
+                return
+            };
+            if matches!(stream.get_state(), StreamState::Failed | StreamState::Terminated) {
+                samples.borrow_mut().take();
+            }
+        }
+    })));
+
+    // a peak-detect stream sends one value per fragment
+    let fragsize = if peaks { 4 } else { rate / 30 * 4 };
+    let attr = BufferAttr { maxlength: u32::MAX, tlength: u32::MAX, prebuf: u32::MAX, minreq: u32::MAX, fragsize };
+    let mut flags = StreamFlagSet::ADJUST_LATENCY | StreamFlagSet::DONT_MOVE | StreamFlagSet::DONT_INHIBIT_AUTO_SUSPEND;
+    if peaks {
+        flags |= StreamFlagSet::PEAK_DETECT;
+    }
+    s.connect_record(Some(device), Some(&attr), flags)
+        .map_err(|_| AudioServiceError::OperationError)?;
+    drop(s);
+    Ok(stream)
+}
+
+// sends the result of a list query once the list ended
+macro_rules! collect {
+    ($tx:expr, |$item:ident| $convert:expr) => {{
+        let mut tx = Some($tx);
+        let mut items = Vec::new();
+        move |result| match result {
+            ListResult::Item($item) => items.extend($convert),
+            ListResult::End => if let Some(tx) = tx.take() {
+                let _ = tx.send(Ok(std::mem::take(&mut items)));
+            },
+            ListResult::Error => if let Some(tx) = tx.take() {
+                let _ = tx.send(Err(AudioServiceError::OperationError));
+            },
+        }
+    }};
+}
+
+// success callback of the setters
+fn done(tx: oneshot::Sender<Result<(), AudioServiceError>>) -> Option<Box<dyn FnMut(bool) + 'static>> {
+    let mut tx = Some(tx);
+    Some(Box::new(move |success| if let Some(tx) = tx.take() {
+        let _ = tx.send(if success { Ok(()) } else { Err(AudioServiceError::OperationError) });
+    }))
+}
+
+// Mainloop and Context aren't Send, so they live on their own thread and
+// the backend only passes jobs to it
+pub struct PulseBackend {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl PulseBackend {
+    fn new_context() -> Result<(Mainloop, Context), AudioServiceError> {
+        let mut proplist = Proplist::new().unwrap();
+        proplist.set_str(pulse::proplist::properties::APPLICATION_NAME, APP_NAME)
+            .unwrap();
+
+        let mut mainloop = Mainloop::new().ok_or(AudioServiceError::NewMainloopError)?;
+
+        let mut context = Context::new_with_proplist(
+            &mainloop,
+            APP_NAME,
+            &proplist
+            ).ok_or(AudioServiceError::NewContextError)?;
+
+        // the state callback only wakes this thread up, the state is checked below
+        let (state_tx, state_rx) = mpsc::channel();
+        context.set_state_callback(Some(Box::new(move || { let _ = state_tx.send(()); })));
+
+        context.connect(None, ContextFlagSet::NOFLAGS, None)
+            .map_err(AudioServiceError::ConnectContextError)?;
+        mainloop.start().map_err(AudioServiceError::StartMainloopError)?;
+
+        // Wait for context to be ready
+        loop {
+            mainloop.lock();
+            let state = context.get_state();
+            mainloop.unlock();
+            match state {
+                pulse::context::State::Ready => { break; },
+                pulse::context::State::Failed |
+                pulse::context::State::Terminated => {
+                    warn!(target: "audio", "Context state failed/terminated");
+                    mainloop.stop();
+                    return Err(AudioServiceError::ContextTerminatedError);
+                },
+                _ => {},
+            }
+            if state_rx.recv().is_err() {
+                mainloop.stop();
+                return Err(AudioServiceError::ContextTerminatedError);
+            }
+        }
+
+        mainloop.lock();
+        context.set_state_callback(None);
+        mainloop.unlock();
+
+        Ok((mainloop, context))
+    }
+
+    // owns mainloop and context until the backend is dropped
+    fn run(jobs: mpsc::Receiver<Job>, ready: oneshot::Sender<Result<(), AudioServiceError>>) {
+        let (mut mainloop, mut context) = match Self::new_context() {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = ready.send(Err(e));
+                return
+            }
+        };
+        let _ = ready.send(Ok(()));
+
+        while let Ok(job) = jobs.recv() {
+            mainloop.lock();
+            job(&mut context);
+            mainloop.unlock();
+        }
+
+        mainloop.lock();
+        context.disconnect();
+        mainloop.unlock();
+        mainloop.stop();
+        debug!(target: "audio", "pulse thread stopped");
+    }
+
+    pub async fn new() -> Result<Self, AudioServiceError> {
+        let (jobs, rx) = mpsc::channel();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        thread::Builder::new()
+            .name(String::from("pulseaudio"))
+            .spawn(move || Self::run(rx, ready_tx))
+            .map_err(|_| AudioServiceError::ThreadError)?;
+        ready_rx.await.map_err(|_| AudioServiceError::ThreadError)??;
+        Ok(Self { jobs })
+    }
+
+    // runs f on the pulse thread, f has to send the result through the given sender
+    async fn call<T: Send + 'static, F>(&self, f: F) -> Result<T, AudioServiceError>
+        where F: FnOnce(&mut Context, oneshot::Sender<Result<T, AudioServiceError>>) + Send + 'static
+    {
+        let (tx, rx) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |context| f(context, tx)))
+            .map_err(|_| AudioServiceError::ThreadError)?;
+        rx.await.map_err(|_| AudioServiceError::ThreadError)?
+    }
+}
+
+impl AudioBackend for PulseBackend {
+    fn name(&self) -> &'static str {
+        "PulseAudio"
+    }
+
+    // the subscription callback runs on the pulse thread, events are handled on tokio
+    fn subscribe(&self) -> BoxFuture<'_, Result<UnboundedReceiver<AudioEvent>, AudioServiceError>> {
+        Box::pin(async move {
+            let (events_tx, events_rx) = unbounded_channel();
+            self.call(move |context, tx| {
+                context.set_subscribe_callback(Some(Box::new(move |facility, operation, index| {
+                    if let Some(facility) = facility {
+                        let _ = events_tx.send(AudioEvent { facility, operation, index });
+                    }
+                })));
+                let mask = InterestMaskSet::SINK | InterestMaskSet::SOURCE | InterestMaskSet::SINK_INPUT |
+                    InterestMaskSet::SOURCE_OUTPUT | InterestMaskSet::SERVER | InterestMaskSet::CARD;
+                context.subscribe(mask, done(tx).unwrap());
+            }).await?;
+            Ok(events_rx)
+        })
+    }
+
+    fn speakers(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>> {
+        Box::pin(self.call(|context, tx| {
+            context.introspect().get_sink_info_list(collect!(tx, |e| Some(StreamEntry::from(e))));
+        }))
+    }
+
+    fn microphones(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>> {
+        Box::pin(self.call(|context, tx| {
+            context.introspect().get_source_info_list(collect!(tx, |e| {
+                if e.proplist.get_str("device.class") == Some("sound".to_string()) {
+                    Some(StreamEntry::from(e))
+                } else {
+                    None
+                }
+            }));
+        }))
+    }
+
+    fn applications(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>> {
+        Box::pin(self.call(|context, tx| {
+            context.introspect().get_sink_input_info_list(collect!(tx, |e| {
+                if is_helper_stream(&e.proplist, e.resample_method.as_deref()) {
+                    None
+                } else {
+                    Some(StreamEntry::from(e))
+                }
+            }));
+        }))
+    }
+
+    fn recorders(&self) -> BoxFuture<'_, Result<Vec<StreamEntry>, AudioServiceError>> {
+        Box::pin(self.call(|context, tx| {
+            context.introspect().get_source_output_info_list(collect!(tx, |e| {
+                if is_helper_stream(&e.proplist, e.resample_method.as_deref()) {
+                    None
+                } else {
+                    Some(StreamEntry::from(e))
+                }
+            }));
+        }))
+    }
+
+    fn cards(&self) -> BoxFuture<'_, Result<Vec<Card>, AudioServiceError>> {
+        Box::pin(self.call(|context, tx| {
+            context.introspect().get_card_info_list(collect!(tx, |e| Some(Card::from(e))));
+        }))
+    }
+
+    fn defaults(&self) -> BoxFuture<'_, Result<(String, String), AudioServiceError>> {
+        Box::pin(self.call(|context, tx| {
+            let mut tx = Some(tx);
+            context.introspect().get_server_info(move |x: &ServerInfo| {
+                let source_name = x.default_source_name.as_deref().unwrap_or_default();
+                let sink_name = x.default_sink_name.as_deref().unwrap_or_default();
+                if let Some(tx) = tx.take() {
+                    let _ = tx.send(Ok((String::from(source_name), String::from(sink_name))));
+                }
+            });
+        }))
+    }
+
+    fn set_default(&self, target: AudioTarget) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |context, tx| {
+            match target {
+                AudioTarget::Speaker(name) => { context.set_default_sink(&name, done(tx).unwrap()); }
+                AudioTarget::Microphone(name) => { context.set_default_source(&name, done(tx).unwrap()); }
+                _ => { let _ = tx.send(Err(AudioServiceError::OperationError)); }
+            }
+        }))
+    }
+
+    fn set_mute(&self, target: AudioTarget, yes: bool) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |context, tx| {
+            let mut introspect = context.introspect();
+            match target {
+                AudioTarget::Speaker(name) => introspect.set_sink_mute_by_name(&name, yes, done(tx)),
+                AudioTarget::Microphone(name) => introspect.set_source_mute_by_name(&name, yes, done(tx)),
+                AudioTarget::App(index) => introspect.set_sink_input_mute(index, yes, done(tx)),
+                AudioTarget::Recording(index) => introspect.set_source_output_mute(index, yes, done(tx)),
+            };
+        }))
+    }
+
+    fn set_volume(&self, target: AudioTarget, volumes: ChannelVolumes) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |context, tx| {
+            let mut introspect = context.introspect();
+            match target {
+                AudioTarget::Speaker(name) => introspect.set_sink_volume_by_name(&name, &volumes, done(tx)),
+                AudioTarget::Microphone(name) => introspect.set_source_volume_by_name(&name, &volumes, done(tx)),
+                AudioTarget::App(index) => introspect.set_sink_input_volume(index, &volumes, done(tx)),
+                AudioTarget::Recording(index) => introspect.set_source_output_volume(index, &volumes, done(tx)),
+            };
+        }))
+    }
+
+    fn set_card_profile(&self, card_name: String, profile: String) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |context, tx| {
+            context.introspect().set_card_profile_by_name(&card_name, &profile, done(tx));
+        }))
+    }
+
+    fn set_port(&self, target: AudioTarget, port: String) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |context, tx| {
+            let mut introspect = context.introspect();
+            match target {
+                AudioTarget::Speaker(name) => { introspect.set_sink_port_by_name(&name, &port, done(tx)); }
+                AudioTarget::Microphone(name) => { introspect.set_source_port_by_name(&name, &port, done(tx)); }
+                _ => { let _ = tx.send(Err(AudioServiceError::OperationError)); }
+            }
+        }))
+    }
+
+    fn move_stream(&self, target: AudioTarget, device: String) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |context, tx| {
+            let mut introspect = context.introspect();
+            match target {
+                AudioTarget::App(index) => { introspect.move_sink_input_by_name(index, &device, done(tx)); }
+                AudioTarget::Recording(index) => { introspect.move_source_output_by_name(index, &device, done(tx)); }
+                _ => { let _ = tx.send(Err(AudioServiceError::OperationError)); }
+            }
+        }))
+    }
+
+    fn open_meter(&self, target: AudioTarget, rate: u32, peaks: bool, samples: UnboundedSender<Vec<f32>>) -> BoxFuture<'_, Result<u32, AudioServiceError>> {
+        Box::pin(self.call(move |context, tx| {
+            let device = match target {
+                // PulseAudio names the monitor source after its sink
+                AudioTarget::Speaker(name) => format!("{}.monitor", name),
+                AudioTarget::Microphone(name) => name,
+                _ => {
+                    let _ = tx.send(Err(AudioServiceError::OperationError));
+                    return
+                }
+            };
+            let result = new_meter(context, &device, rate, peaks, samples).map(|stream| {
+                let id = NEXT_METER.with(|x| x.replace(x.get() + 1));
+                METERS.with(|x| x.borrow_mut().insert(id, stream));
+                id
+            });
+            let _ = tx.send(result);
+        }))
+    }
+
+    fn close_meter(&self, id: u32) -> BoxFuture<'_, Result<(), AudioServiceError>> {
+        Box::pin(self.call(move |_, tx| {
+            let Some(stream) = METERS.with(|x| x.borrow_mut().remove(&id)) else {
+                let _ = tx.send(Err(AudioServiceError::OperationError));
+                return
+            };
+            let mut stream = stream.borrow_mut();
+            stream.set_read_callback(None);
+            stream.set_state_callback(None);
+            let _ = tx.send(stream.disconnect().map_err(|_| AudioServiceError::OperationError));
+        }))
+    }
+}
@@ -0,0 +1,502 @@
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::{broadcast::{channel, Receiver, Sender}, RwLock};
+
+use super::compositor::{Compositor, CompositorCommand, CompositorEvent, CompositorOutput, CompositorWindow, CompositorWorkspace};
+use super::utils::BoxFuture;
+
+
+// i3-IPC, see sway-ipc(7): "i3-ipc" <payload length> <type> <payload>,
+// both integers 32 bit in native byte order
+const MAGIC: &[u8; 6] = b"i3-ipc";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum MessageType {
+    RunCommand = 0,
+    GetWorkspaces = 1,
+    Subscribe = 2,
+    GetOutputs = 3,
+    GetTree = 4,
+    GetVersion = 7,
+    GetBindingState = 12,
+}
+
+const EVENT_WORKSPACE: u32 = 0x80000000;
+const EVENT_OUTPUT: u32 = 0x80000001;
+const EVENT_MODE: u32 = 0x80000002;
+const EVENT_WINDOW: u32 = 0x80000003;
+const EVENT_SHUTDOWN: u32 = 0x80000006;
+
+async fn write_message(stream: &mut UnixStream, kind: u32, payload: &[u8]) -> std::io::Result<()> {
+    let mut msg = Vec::with_capacity(14 + payload.len());
+    msg.extend_from_slice(MAGIC);
+    msg.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    msg.extend_from_slice(&kind.to_ne_bytes());
+    msg.extend_from_slice(payload);
+    stream.write_all(&msg).await
+}
+
+async fn read_message(stream: &mut UnixStream) -> std::io::Result<(u32, Vec<u8>)> {
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).await?;
+    if &header[..6] != MAGIC {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not an i3-ipc message"));
+    }
+    let len = u32::from_ne_bytes([header[6], header[7], header[8], header[9]]);
+    let kind = u32::from_ne_bytes([header[10], header[11], header[12], header[13]]);
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload).await?;
+    Ok((kind, payload))
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Rect {
+    pub x: i64,
+    pub y: i64,
+    pub width: i64,
+    pub height: i64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SwayWorkspace {
+    pub id: i64,
+    pub num: i64,
+    pub name: String,
+    pub visible: bool,
+    pub focused: bool,
+    pub urgent: bool,
+    pub rect: Rect,
+    pub output: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SwayOutput {
+    pub name: String,
+    pub make: String,
+    pub model: String,
+    pub serial: String,
+    pub active: bool,
+    pub scale: f64,
+    pub current_workspace: Option<String>,
+    pub rect: Rect,
+    pub focused: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WindowProperties {
+    pub class: Option<String>,
+    pub instance: Option<String>,
+    pub title: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SwayNode {
+    pub id: i64,
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub type_: String, // root, output, workspace, con or floating_con
+    pub focused: bool,
+    pub urgent: bool,
+    pub rect: Rect,
+    pub app_id: Option<String>, // None for xwayland windows
+    pub pid: Option<i64>,
+    pub window_properties: Option<WindowProperties>,
+    pub fullscreen_mode: i64,
+    pub nodes: Vec<SwayNode>,
+    pub floating_nodes: Vec<SwayNode>,
+}
+
+impl SwayNode {
+    pub fn is_window(&self) -> bool {
+        (self.type_ == "con" || self.type_ == "floating_con") && self.pid.is_some()
+    }
+
+    // windows below this node together with the name of their workspace
+    pub fn windows(&self) -> Vec<(String, &SwayNode)> {
+        let mut result = Vec::new();
+        self.collect_windows(None, &mut result);
+        result
+    }
+
+    fn collect_windows<'a>(&'a self, workspace: Option<&str>, result: &mut Vec<(String, &'a SwayNode)>) {
+        let workspace = if self.type_ == "workspace" { self.name.as_deref() } else { workspace };
+        if self.is_window() {
+            result.push((workspace.unwrap_or_default().to_string(), self));
+        }
+        for child in self.nodes.iter().chain(self.floating_nodes.iter()) {
+            child.collect_windows(workspace, result);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct CommandReply {
+    success: bool,
+    error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct ChangeEvent {
+    change: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SwayEvent {
+    Workspace(String), // change: focus, init, empty, rename, urgent, reload, move
+    Window(String), // change: new, close, focus, title, fullscreen_mode, move, floating, urgent, mark
+    Output,
+    Mode(String),
+    Shutdown(String), // exit or restart
+    Unknown(u32),
+}
+
+impl SwayEvent {
+    pub fn parse(kind: u32, payload: &[u8]) -> Self {
+        let change = serde_json::from_slice::<ChangeEvent>(payload).map(|c| c.change).unwrap_or_default();
+        match kind {
+            EVENT_WORKSPACE => SwayEvent::Workspace(change),
+            EVENT_WINDOW => SwayEvent::Window(change),
+            EVENT_OUTPUT => SwayEvent::Output,
+            EVENT_MODE => SwayEvent::Mode(change),
+            EVENT_SHUTDOWN => SwayEvent::Shutdown(change),
+            _ => SwayEvent::Unknown(kind)
+        }
+    }
+
+    pub fn compositor_event(&self) -> Option<CompositorEvent> {
+        match self {
+            SwayEvent::Workspace(change) if change == "reload" => Some(CompositorEvent::Reloaded),
+            SwayEvent::Workspace(_) => Some(CompositorEvent::Workspaces),
+            SwayEvent::Window(change) if change == "focus" => Some(CompositorEvent::Focus),
+            SwayEvent::Window(_) => Some(CompositorEvent::Windows),
+            SwayEvent::Output => Some(CompositorEvent::Outputs),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SwayData {
+    pub workspaces: Vec<SwayWorkspace>,
+    pub outputs: Vec<SwayOutput>,
+    pub tree: SwayNode,
+    pub mode: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SwaySender {
+    pub event: Sender<SwayEvent>,
+    pub compositor: Sender<CompositorEvent>,
+    pub changed: Sender<Arc<RwLock<SwayData>>>,
+    pub workspaces: Sender<Arc<RwLock<SwayData>>>,
+    pub outputs: Sender<Arc<RwLock<SwayData>>>,
+    pub tree: Sender<Arc<RwLock<SwayData>>>,
+    pub mode: Sender<Arc<RwLock<SwayData>>>,
+}
+
+impl SwaySender {
+    fn new() -> Self {
+        Self {
+            event: channel(30).0,
+            compositor: channel(30).0,
+            changed: channel(30).0,
+            workspaces: channel(30).0,
+            outputs: channel(30).0,
+            tree: channel(30).0,
+            mode: channel(30).0,
+        }
+    }
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub struct SwayService {
+    socket: PathBuf,
+    pub data: Arc<RwLock<SwayData>>,
+    pub sender: SwaySender,
+}
+
+impl SwayService {
+    pub async fn new() -> std::io::Result<Arc<RwLock<Self>>> {
+        let socket = env::var("SWAYSOCK")
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::NotFound, "Sway is not running (SWAYSOCK not set)"))?;
+
+        let res = Arc::new(RwLock::new(SwayService {
+            socket: socket.into(),
+            data: Arc::new(RwLock::new(SwayData::default())),
+            sender: SwaySender::new(),
+        }));
+
+        {
+            let service = res.clone();
+
+            // subscribe before seeding, so no event between the two gets lost
+            let stream = res.read().await.subscribe_events().await?;
+            res.write().await.sync().await?;
+
+            tokio::spawn(async move {
+                let mut stream = Ok(stream);
+                let mut backoff = MIN_BACKOFF;
+                loop {
+                    match stream {
+                        Ok(s) => {
+                            let connected = Instant::now();
+                            Self::listen(&service, s).await;
+                            // only a connection that lasted resets the backoff, a read failing
+                            // right after connecting would reconnect in a tight loop otherwise
+                            if connected.elapsed() > MAX_BACKOFF {
+                                backoff = MIN_BACKOFF;
+                            }
+                            warn!(target: "sway", "event socket closed, reconnecting in {:?}", backoff);
+                        }
+                        Err(e) => {
+                            debug!(target: "sway", "couldn't connect: {:?}, retrying in {:?}", e, backoff);
+                        }
+                    }
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    let r = service.read().await.subscribe_events().await;
+                    stream = match r {
+                        Ok(s) => service.write().await.sync().await.map(|_| s),
+                        Err(e) => Err(e)
+                    };
+                }
+            });
+        }
+
+        Ok(res)
+    }
+
+    async fn subscribe_events(&self) -> std::io::Result<UnixStream> {
+        let mut stream = UnixStream::connect(&self.socket).await?;
+        write_message(&mut stream, MessageType::Subscribe as u32, br#"["workspace","window","output","mode","shutdown"]"#).await?;
+        let (_, reply) = read_message(&mut stream).await?;
+        let reply: CommandReply = serde_json::from_slice(&reply)?;
+        if !reply.success {
+            return Err(std::io::Error::other("subscribing to sway events failed"));
+        }
+        Ok(stream)
+    }
+
+    // returns once the event socket is closed or fails
+    async fn listen(service: &Arc<RwLock<Self>>, mut stream: UnixStream) {
+        loop {
+            let (kind, payload) = match read_message(&mut stream).await {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!(target: "sway", "event read failed: {:?}", e);
+                    return;
+                }
+            };
+            let event = SwayEvent::parse(kind, &payload);
+            let mut writer = service.write().await;
+            if let Err(e) = writer.handle_event(&event).await {
+                warn!(target: "sway", "couldn't update state for {:?}: {:?}", event, e);
+            }
+            if let Some(e) = event.compositor_event() {
+                let _ = writer.sender.compositor.send(e);
+            }
+            if writer.sender.event.send(event).is_err() {
+                debug!(target: "sway", "No receiver");
+            }
+        }
+    }
+
+    // sway answers every request on the same connection, one connection per request keeps it simple
+    pub async fn message(&self, kind: MessageType, payload: &str) -> std::io::Result<Vec<u8>> {
+        let mut stream = UnixStream::connect(&self.socket).await?;
+        write_message(&mut stream, kind as u32, payload.as_bytes()).await?;
+        let (_, reply) = read_message(&mut stream).await?;
+        Ok(reply)
+    }
+
+    pub async fn message_json<T: DeserializeOwned>(&self, kind: MessageType, payload: &str) -> std::io::Result<T> {
+        let reply = self.message(kind, payload).await?;
+        Ok(serde_json::from_slice(&reply)?)
+    }
+
+    // several commands can be separated by `;` or `,`
+    pub async fn run_command(&self, cmd: &str) -> std::io::Result<()> {
+        let replies: Vec<CommandReply> = self.message_json(MessageType::RunCommand, cmd).await?;
+        match replies.into_iter().find(|r| !r.success) {
+            Some(r) => Err(std::io::Error::other(r.error.unwrap_or_default())),
+            None => Ok(())
+        }
+    }
+
+    pub async fn get_workspaces(&self) -> std::io::Result<Vec<SwayWorkspace>> {
+        self.message_json(MessageType::GetWorkspaces, "").await
+    }
+
+    pub async fn get_outputs(&self) -> std::io::Result<Vec<SwayOutput>> {
+        self.message_json(MessageType::GetOutputs, "").await
+    }
+
+    pub async fn get_tree(&self) -> std::io::Result<SwayNode> {
+        self.message_json(MessageType::GetTree, "").await
+    }
+
+    pub async fn sync(&mut self) -> std::io::Result<()> {
+        let workspaces = self.get_workspaces().await?;
+        self.update_workspaces(workspaces).await;
+        let outputs = self.get_outputs().await?;
+        self.update_outputs(outputs).await;
+        let tree = self.get_tree().await?;
+        self.update_tree(tree).await;
+        self.update().await;
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, event: &SwayEvent) -> std::io::Result<()> {
+        match event {
+            SwayEvent::Workspace(change) => {
+                let workspaces = self.get_workspaces().await?;
+                self.update_workspaces(workspaces).await;
+                // the current workspace and focus of the outputs follow workspace switches
+                if matches!(change.as_str(), "focus" | "move") {
+                    let outputs = self.get_outputs().await?;
+                    self.update_outputs(outputs).await;
+                }
+                let tree = self.get_tree().await?;
+                self.update_tree(tree).await;
+            }
+            SwayEvent::Window(_) => {
+                let tree = self.get_tree().await?;
+                self.update_tree(tree).await;
+            }
+            SwayEvent::Output => {
+                let outputs = self.get_outputs().await?;
+                self.update_outputs(outputs).await;
+                let workspaces = self.get_workspaces().await?;
+                self.update_workspaces(workspaces).await;
+            }
+            SwayEvent::Mode(mode) => {
+                self.update_mode(mode.clone()).await;
+            }
+            _ => return Ok(())
+        }
+        self.update().await;
+        Ok(())
+    }
+
+    update!(update_workspaces, workspaces, Vec<SwayWorkspace>);
+    update!(update_outputs, outputs, Vec<SwayOutput>);
+    update!(update_tree, tree, SwayNode);
+    update!(update_mode, mode, String);
+
+    async fn update(&mut self) {
+        match self.sender.changed.send(self.data.clone()) {
+            Ok(_) => {},
+            Err(_) => {debug!(target: "sway", "No receiver");}
+        }
+    }
+}
+
+impl From<(String, &SwayNode)> for CompositorWindow {
+    fn from((workspace, node): (String, &SwayNode)) -> Self {
+        let properties = node.window_properties.clone().unwrap_or_default();
+        Self {
+            id: node.id.to_string(),
+            title: node.name.clone().or(properties.title).unwrap_or_default(),
+            app_id: node.app_id.clone().or(properties.class).unwrap_or_default(),
+            pid: node.pid.unwrap_or(-1),
+            workspace,
+            focused: node.focused,
+            floating: node.type_ == "floating_con",
+            fullscreen: node.fullscreen_mode != 0,
+        }
+    }
+}
+
+// quotes an argument for the sway command language
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Compositor for SwayService {
+    fn name(&self) -> &'static str {
+        "Sway"
+    }
+
+    fn workspaces(&self) -> BoxFuture<'_, std::io::Result<Vec<CompositorWorkspace>>> {
+        Box::pin(async move {
+            // num is -1 for all named workspaces, so it's only used to put the numbered ones first
+            let mut workspaces = self.data.read().await.workspaces.clone();
+            workspaces.sort_by_key(|w| (w.num < 0, w.num));
+            Ok(workspaces.iter().map(|w| CompositorWorkspace {
+                id: w.id,
+                name: w.name.clone(),
+                output: w.output.clone(),
+                focused: w.focused,
+                visible: w.visible,
+                urgent: w.urgent,
+            }).collect())
+        })
+    }
+
+    fn windows(&self) -> BoxFuture<'_, std::io::Result<Vec<CompositorWindow>>> {
+        Box::pin(async move {
+            Ok(self.data.read().await.tree.windows().into_iter().map(CompositorWindow::from).collect())
+        })
+    }
+
+    fn outputs(&self) -> BoxFuture<'_, std::io::Result<Vec<CompositorOutput>>> {
+        Box::pin(async move {
+            Ok(self.data.read().await.outputs.iter().filter(|o| o.active).map(|o| CompositorOutput {
+                name: o.name.clone(),
+                description: format!("{} {} {}", o.make, o.model, o.serial),
+                x: o.rect.x,
+                y: o.rect.y,
+                width: o.rect.width,
+                height: o.rect.height,
+                scale: o.scale,
+                focused: o.focused,
+                active_workspace: o.current_workspace.clone().unwrap_or_default(),
+            }).collect())
+        })
+    }
+
+    fn focused_window(&self) -> BoxFuture<'_, std::io::Result<Option<CompositorWindow>>> {
+        Box::pin(async move {
+            Ok(self.data.read().await.tree.windows().into_iter().find(|(_, n)| n.focused).map(CompositorWindow::from))
+        })
+    }
+
+    fn command(&self, cmd: CompositorCommand) -> BoxFuture<'_, std::io::Result<()>> {
+        let workspace = |name: String| match name.parse::<i64>() {
+            Ok(num) => format!("number {num}"),
+            Err(_) => quote(&name),
+        };
+        let cmd = match cmd {
+            CompositorCommand::FocusWorkspace(name) => format!("workspace {}", workspace(name)),
+            CompositorCommand::MoveToWorkspace(name) => format!("move container to workspace {}", workspace(name)),
+            CompositorCommand::FocusWindow(id) => format!("[con_id={id}] focus"),
+            CompositorCommand::CloseWindow(id) => format!("[con_id={id}] kill"),
+            CompositorCommand::FocusOutput(name) => format!("focus output {}", quote(&name)),
+            CompositorCommand::ToggleFloating => "floating toggle".to_string(),
+            CompositorCommand::ToggleFullscreen => "fullscreen toggle".to_string(),
+            CompositorCommand::Exec(cmd) => format!("exec {}", quote(&cmd)),
+        };
+        Box::pin(async move { self.run_command(&cmd).await })
+    }
+
+    fn subscribe(&self) -> Receiver<CompositorEvent> {
+        self.sender.compositor.subscribe()
+    }
+}
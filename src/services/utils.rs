@@ -1,10 +1,13 @@
-use std::{borrow::Cow, env::VarError, path::Path, process::Command};
+use std::{borrow::Cow, env::VarError, future::Future, path::Path, pin::Pin, process::Command};
 
 use log::{info, warn, debug};
 use tokio::sync::{mpsc};
 use notify::{Watcher, RecursiveMode};
 use tokio::runtime::Handle;
 
+// a boxed future, for async methods of object safe traits
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
 
 macro_rules! update {
     ($method_name:ident, $i:ident, $t:ty) => {